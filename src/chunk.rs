@@ -1,11 +1,37 @@
+use std::io::{self, Write};
+use std::rc::Rc;
+
 use crate::value::{ValueArray, Value};
 use crate::opcode::OpCode;
+use crate::scanner::Scanner;
+use crate::token::Span;
+
+// largest index that fits in OP_CONSTANT_LONG's 24-bit operand
+const MAX_LONG_CONSTANT: usize = (1 << 24) - 1;
+
+// the line of every byte from offset up to the next run's offset
+#[derive(Debug, Clone)]
+struct LineStart {
+    offset: usize,
+    line: usize,
+}
+
+// the first byte of the token that produced the code from offset on, the
+// rest of the span is only needed for errors so it is rebuilt from the source
+#[derive(Debug, Clone)]
+struct SpanStart {
+    offset: u32,
+    start: u32,
+}
 
 #[derive(Debug, Clone)]
 pub struct Chunk {
     pub code: Vec<u8>,
     pub values: ValueArray,
-    pub lines: Vec<usize>,
+    lines: Vec<LineStart>,
+    starts: Vec<SpanStart>,
+    // the script the chunk was compiled from
+    pub source: Rc<String>,
 }
 
 impl Chunk {
@@ -14,64 +40,253 @@ impl Chunk {
             code: Vec::new(),
             values: ValueArray::new(),
             lines: Vec::new(),
+            starts: Vec::new(),
+            source: Rc::default(),
         }
     }
 
-    pub fn write_chunk(&mut self, byte: u8, line: usize) {
-        self.lines.push(line);
+    pub fn write_chunk(&mut self, byte: u8, span: Span) {
+        let offset = self.code.len();
+        if self.lines.last().is_none_or(|start| start.line != span.line) {
+            self.lines.push(LineStart { offset, line: span.line });
+        }
+        if self.starts.last().is_none_or(|start| start.start as usize != span.start) {
+            self.starts.push(SpanStart { offset: offset as u32, start: span.start as u32 });
+        }
         self.code.push(byte);
     }
 
-    pub fn free(&mut self) {
-        self.code.clear();
-        self.values.free();
-        self.lines.clear();
+    pub fn get_span(&self, offset: usize) -> Span {
+        let run = self.starts.partition_point(|start| start.offset as usize <= offset);
+        let start = self.starts[run - 1].start as usize;
+        let line_start = self.source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let column = self.source[line_start..start].chars().count() + 1;
+        let end = Scanner::token_end(Rc::clone(&self.source), start);
+        Span::new(start, end, self.get_line(offset), column)
     }
 
-    fn simple_instruction(&self, name: &str, offset: usize) -> usize {
-        println!("{}", name);
-        offset + 1
+    pub fn get_line(&self, offset: usize) -> usize {
+        let run = self.lines.partition_point(|start| start.offset <= offset);
+        self.lines[run - 1].line
     }
 
-    fn constant_instruction(&self, name: &str, offset: usize) -> usize {
-        let constant = self.code[offset + 1];
-        print!("{} {:4} ", name, constant);
-        println!("{:?}", self.values.data[constant as usize]);
-        offset + 2
+    // bytes owned by the chunk's tables, the source is shared so it is left out
+    pub fn heap_size(&self) -> usize {
+        self.code.len()
+            + self.values.data.len() * std::mem::size_of::<Value>()
+            + self.lines.len() * std::mem::size_of::<LineStart>()
+            + self.starts.len() * std::mem::size_of::<SpanStart>()
     }
 
-    fn disassemble_instruction(&self, offset: usize) -> usize {
-        print!("{:04} ", offset);
+    // emits an instruction taking a constant index, switching to the long
+    // form with a 24-bit operand once the index no longer fits in a byte,
+    // returns false once the pool is too big for even a long operand
+    pub fn write_indexed(&mut self, op: OpCode, constant: usize, span: Span) -> bool {
+        if constant <= u8::MAX as usize {
+            self.write_chunk(op as u8, span);
+            self.write_chunk(constant as u8, span);
+            return true;
+        }
+        let long_op = match op.long_form() {
+            Some(long_op) if constant <= MAX_LONG_CONSTANT => long_op,
+            _ => return false,
+        };
+        self.write_chunk(long_op as u8, span);
+        for byte in &(constant as u32).to_be_bytes()[1..] {
+            self.write_chunk(*byte, span);
+        }
+        true
+    }
 
-        if (offset > 0) && (self.lines[offset] == self.lines[offset - 1]) {
-            print!("   | ");
+    // the constant index after the opcode at offset and the offset past it
+    fn read_index(&self, offset: usize) -> (usize, usize) {
+        let op: OpCode = self.code[offset].into();
+        if op.is_long() {
+            let bytes = [0, self.code[offset + 1], self.code[offset + 2], self.code[offset + 3]];
+            (u32::from_be_bytes(bytes) as usize, offset + 4)
         } else {
-            print!("{:4} ", self.lines[offset]);
+            (self.code[offset + 1] as usize, offset + 2)
+        }
+    }
+
+    fn simple_instruction(&self, out: &mut dyn Write, name: &str, offset: usize) -> io::Result<usize> {
+        writeln!(out, "{}", name)?;
+        Ok(offset + 1)
+    }
+
+    fn constant_instruction(&self, out: &mut dyn Write, name: &str, offset: usize) -> io::Result<usize> {
+        let (constant, next) = self.read_index(offset);
+        write!(out, "{} {:4} ", name, constant)?;
+        writeln!(out, "'{}'", self.values.data[constant])?;
+        Ok(next)
+    }
+
+    fn byte_instruction(&self, out: &mut dyn Write, name: &str, offset: usize) -> io::Result<usize> {
+        let slot = self.code[offset + 1];
+        writeln!(out, "{} {:4}", name, slot)?;
+        Ok(offset + 2)
+    }
+
+    fn invoke_instruction(&self, out: &mut dyn Write, name: &str, offset: usize) -> io::Result<usize> {
+        let (constant, next) = self.read_index(offset);
+        let arg_count = self.code[next];
+        write!(out, "{} ({} args) {:4} ", name, arg_count, constant)?;
+        writeln!(out, "'{}'", self.values.data[constant])?;
+        Ok(next + 1)
+    }
+
+    fn jump_instruction(&self, out: &mut dyn Write, name: &str, sign: isize, offset: usize) -> io::Result<usize> {
+        let jump = u16::from_be_bytes([self.code[offset + 1], self.code[offset + 2]]);
+        let target = offset as isize + 3 + sign * jump as isize;
+        writeln!(out, "{} {:4} -> {}", name, offset, target)?;
+        Ok(offset + 3)
+    }
+
+    fn closure_instruction(&self, out: &mut dyn Write, name: &str, offset: usize) -> io::Result<usize> {
+        let (constant, next) = self.read_index(offset);
+        let value = &self.values.data[constant];
+        writeln!(out, "{} {:4} '{}'", name, constant, value)?;
+
+        let upvalue_count = match value {
+            Value::Function(function) => function.upvalue_count,
+            _ => 0,
+        };
+        let mut offset = next;
+        for _ in 0..upvalue_count {
+            let is_local = self.code[offset];
+            let index = self.code[offset + 1];
+            let kind = if is_local == 1 { "local" } else { "upvalue" };
+            writeln!(out, "{:04}    |   {} {}", offset, kind, index)?;
+            offset += 2;
+        }
+        Ok(offset)
+    }
+
+    pub fn disassemble_instruction(&self, out: &mut dyn Write, offset: usize) -> io::Result<usize> {
+        write!(out, "{:04} ", offset)?;
+
+        let line = self.get_line(offset);
+        if (offset > 0) && (line == self.get_line(offset - 1)) {
+            write!(out, "   | ")?;
+        } else {
+            write!(out, "{:4} ", line)?;
         }
 
         let instruction = self.code[offset].into();
         match instruction {
-            OpCode::OP_CONSTANT => self.constant_instruction("OP_CONSTANT", offset),
-            OpCode::OP_ADD => self.simple_instruction("OP_ADD", offset),
-            OpCode::OP_SUBTRACT => self.simple_instruction("OP_SUBTRACT", offset),
-            OpCode::OP_MULTIPLY => self.simple_instruction("OP_MULTIPLY", offset),
-            OpCode::OP_DIVIDE => self.simple_instruction("OP_DIVIDE", offset),
-            OpCode::OP_NEGATE => self.simple_instruction("OP_NEGATE", offset),
-            OpCode::OP_RETURN => self.simple_instruction("OP_RETURN", offset),
+            OpCode::OP_CONSTANT => self.constant_instruction(out, "OP_CONSTANT", offset),
+            OpCode::OP_ADD => self.simple_instruction(out, "OP_ADD", offset),
+            OpCode::OP_SUBTRACT => self.simple_instruction(out, "OP_SUBTRACT", offset),
+            OpCode::OP_MULTIPLY => self.simple_instruction(out, "OP_MULTIPLY", offset),
+            OpCode::OP_DIVIDE => self.simple_instruction(out, "OP_DIVIDE", offset),
+            OpCode::OP_NEGATE => self.simple_instruction(out, "OP_NEGATE", offset),
+            OpCode::OP_RETURN => self.simple_instruction(out, "OP_RETURN", offset),
+            OpCode::OP_NIL => self.simple_instruction(out, "OP_NIL", offset),
+            OpCode::OP_TRUE => self.simple_instruction(out, "OP_TRUE", offset),
+            OpCode::OP_FALSE => self.simple_instruction(out, "OP_FALSE", offset),
+            OpCode::OP_NOT => self.simple_instruction(out, "OP_NOT", offset),
+            OpCode::OP_EQUAL => self.simple_instruction(out, "OP_EQUAL", offset),
+            OpCode::OP_GREATER => self.simple_instruction(out, "OP_GREATER", offset),
+            OpCode::OP_LESS => self.simple_instruction(out, "OP_LESS", offset),
+            OpCode::OP_PRINT => self.simple_instruction(out, "OP_PRINT", offset),
+            OpCode::OP_POP => self.simple_instruction(out, "OP_POP", offset),
+            OpCode::OP_DEFINE_GLOBAL => self.constant_instruction(out, "OP_DEFINE_GLOBAL", offset),
+            OpCode::OP_GET_GLOBAL => self.constant_instruction(out, "OP_GET_GLOBAL", offset),
+            OpCode::OP_SET_GLOBAL => self.constant_instruction(out, "OP_SET_GLOBAL", offset),
+            OpCode::OP_GET_LOCAL => self.byte_instruction(out, "OP_GET_LOCAL", offset),
+            OpCode::OP_SET_LOCAL => self.byte_instruction(out, "OP_SET_LOCAL", offset),
+            OpCode::OP_JUMP => self.jump_instruction(out, "OP_JUMP", 1, offset),
+            OpCode::OP_JUMP_IF_FALSE => self.jump_instruction(out, "OP_JUMP_IF_FALSE", 1, offset),
+            OpCode::OP_LOOP => self.jump_instruction(out, "OP_LOOP", -1, offset),
+            OpCode::OP_CALL => self.byte_instruction(out, "OP_CALL", offset),
+            OpCode::OP_CLOSURE => self.closure_instruction(out, "OP_CLOSURE", offset),
+            OpCode::OP_GET_UPVALUE => self.byte_instruction(out, "OP_GET_UPVALUE", offset),
+            OpCode::OP_SET_UPVALUE => self.byte_instruction(out, "OP_SET_UPVALUE", offset),
+            OpCode::OP_CLOSE_UPVALUE => self.simple_instruction(out, "OP_CLOSE_UPVALUE", offset),
+            OpCode::OP_CLASS => self.constant_instruction(out, "OP_CLASS", offset),
+            OpCode::OP_GET_PROPERTY => self.constant_instruction(out, "OP_GET_PROPERTY", offset),
+            OpCode::OP_SET_PROPERTY => self.constant_instruction(out, "OP_SET_PROPERTY", offset),
+            OpCode::OP_METHOD => self.constant_instruction(out, "OP_METHOD", offset),
+            OpCode::OP_INVOKE => self.invoke_instruction(out, "OP_INVOKE", offset),
+            OpCode::OP_INHERIT => self.simple_instruction(out, "OP_INHERIT", offset),
+            OpCode::OP_GET_SUPER => self.constant_instruction(out, "OP_GET_SUPER", offset),
+            OpCode::OP_SUPER_INVOKE => self.invoke_instruction(out, "OP_SUPER_INVOKE", offset),
+            OpCode::OP_CONSTANT_LONG => self.constant_instruction(out, "OP_CONSTANT_LONG", offset),
+            OpCode::OP_DEFINE_GLOBAL_LONG => self.constant_instruction(out, "OP_DEFINE_GLOBAL_LONG", offset),
+            OpCode::OP_GET_GLOBAL_LONG => self.constant_instruction(out, "OP_GET_GLOBAL_LONG", offset),
+            OpCode::OP_SET_GLOBAL_LONG => self.constant_instruction(out, "OP_SET_GLOBAL_LONG", offset),
+            OpCode::OP_CLOSURE_LONG => self.closure_instruction(out, "OP_CLOSURE_LONG", offset),
+            OpCode::OP_CLASS_LONG => self.constant_instruction(out, "OP_CLASS_LONG", offset),
+            OpCode::OP_GET_PROPERTY_LONG => self.constant_instruction(out, "OP_GET_PROPERTY_LONG", offset),
+            OpCode::OP_SET_PROPERTY_LONG => self.constant_instruction(out, "OP_SET_PROPERTY_LONG", offset),
+            OpCode::OP_METHOD_LONG => self.constant_instruction(out, "OP_METHOD_LONG", offset),
+            OpCode::OP_INVOKE_LONG => self.invoke_instruction(out, "OP_INVOKE_LONG", offset),
+            OpCode::OP_GET_SUPER_LONG => self.constant_instruction(out, "OP_GET_SUPER_LONG", offset),
+            OpCode::OP_SUPER_INVOKE_LONG => self.invoke_instruction(out, "OP_SUPER_INVOKE_LONG", offset),
         }
     }
 
-    pub fn disassemble(&self, name: &str) {
-        println!("== {} ==", name);
+    pub fn disassemble(&self, out: &mut dyn Write, name: &str) -> io::Result<()> {
+        writeln!(out, "== {} ==", name)?;
 
         let mut offset = 0;
         while offset < self.code.len() {
-            offset = self.disassemble_instruction(offset);
+            offset = self.disassemble_instruction(out, offset)?;
         }
+        Ok(())
     }
 
     pub fn add_constant(&mut self, value: Value) -> usize {
         self.values.write_value(value);
         self.values.data.len() - 1
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_table_stores_one_run_per_line() {
+        let source = "a + b;\nc;\n\n\nd(e);\nfoo;";
+        // a byte per token, with the spans the scanner gives them
+        let spans = [
+            Span::new(0, 1, 1, 1),
+            Span::new(2, 3, 1, 3),
+            Span::new(4, 5, 1, 5),
+            Span::new(7, 8, 2, 1),
+            Span::new(12, 13, 5, 1),
+            Span::new(14, 15, 5, 3),
+            Span::new(18, 21, 6, 1),
+        ];
+        let mut chunk = Chunk::new();
+        chunk.source = Rc::new(source.to_string());
+        for (byte, span) in spans.iter().enumerate() {
+            chunk.write_chunk(byte as u8, *span);
+        }
+        assert_eq!(chunk.lines.len(), 4);
+        let lines: Vec<usize> = (0..chunk.code.len()).map(|offset| chunk.get_line(offset)).collect();
+        assert_eq!(lines, vec![1, 1, 1, 2, 5, 5, 6]);
+        let found: Vec<Span> = (0..chunk.code.len()).map(|offset| chunk.get_span(offset)).collect();
+        assert_eq!(found, spans);
+
+        // operand bytes share their instruction's span and add no runs
+        chunk.write_chunk(0, Span::new(18, 21, 6, 1));
+        assert_eq!((chunk.lines.len(), chunk.starts.len()), (4, 7));
+    }
+
+    #[test]
+    fn wide_constant_indexes_switch_to_the_long_form() {
+        let mut chunk = Chunk::new();
+        let span = Span::new(0, 1, 1, 1);
+        assert!(chunk.write_indexed(OpCode::OP_GET_GLOBAL, 0x12, span));
+        assert!(chunk.write_indexed(OpCode::OP_GET_GLOBAL, 0x012345, span));
+        assert_eq!(
+            chunk.code,
+            vec![OpCode::OP_GET_GLOBAL as u8, 0x12, OpCode::OP_GET_GLOBAL_LONG as u8, 0x01, 0x23, 0x45]
+        );
+        assert_eq!(chunk.read_index(2), (0x012345, 6));
+        assert!(!chunk.write_indexed(OpCode::OP_GET_LOCAL, 0x100, span));
+        assert!(!chunk.write_indexed(OpCode::OP_CONSTANT, MAX_LONG_CONSTANT + 1, span));
+    }
+}
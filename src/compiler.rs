@@ -1,37 +1,180 @@
+use std::rc::Rc;
+
 use crate::scanner::Scanner;
-use crate::token::{Token, TokenType};
+use crate::token::{Span, Token, TokenType};
 use crate::chunk::Chunk;
 use crate::parser::{Parser, Precedence};
 use crate::opcode::OpCode;
 use crate::value::Value;
+use crate::gc::Gc;
+use crate::object::{ObjFunction, ObjString};
+use crate::vm::VM;
+use crate::diagnostic::Diagnostic;
+
+const UINT8_COUNT: usize = u8::MAX as usize + 1;
+
+struct Local {
+    name: Token,
+    // None until the variable's initializer has been compiled
+    depth: Option<usize>,
+    // captured locals are moved to the heap when they go out of scope
+    is_captured: bool,
+}
+
+struct Upvalue {
+    // local slot in the enclosing function if is_local, otherwise its upvalue index
+    index: u8,
+    is_local: bool,
+}
+
+#[derive(PartialEq, Clone, Copy)]
+enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+// compilation state of a single function, nested function declarations push a new one
+struct FunctionState {
+    function: ObjFunction,
+    function_type: FunctionType,
+    locals: Vec<Local>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: usize,
+}
+
+impl FunctionState {
+    fn new(function_type: FunctionType, name: Option<Gc<ObjString>>) -> Self {
+        // slot zero holds the function being called, or the receiver for methods
+        let slot_zero_name = match function_type {
+            FunctionType::Initializer | FunctionType::Method => "this",
+            FunctionType::Function | FunctionType::Script => "",
+        };
+        let slot_zero = Local {
+            name: Token::new(TokenType::IDENTIFIER, slot_zero_name.to_string(), Span::default()),
+            depth: Some(0),
+            is_captured: false,
+        };
+        Self {
+            function: ObjFunction::new(name),
+            function_type,
+            locals: vec![slot_zero],
+            upvalues: Vec::new(),
+            scope_depth: 0,
+        }
+    }
+}
 
-pub struct Compiler {
+// tracks the class whose body is being compiled
+struct ClassState {
+    has_superclass: bool,
+}
+
+pub struct Compiler<'a> {
     parser: Parser,
-    pub current_chunk: Chunk,
+    source: Rc<String>,
+    vm: &'a mut VM,
+    states: Vec<FunctionState>,
+    classes: Vec<ClassState>,
 }
 
-impl Compiler {
-    pub fn new(source: String) -> Self {
+impl<'a> Compiler<'a> {
+    pub fn new(source: Rc<String>, vm: &'a mut VM) -> Self {
         Self {
-            parser: Parser::new(Scanner::new(source)),
-            current_chunk: Chunk::new(),
+            parser: Parser::new(Scanner::new(Rc::clone(&source))),
+            source,
+            vm,
+            states: vec![FunctionState::new(FunctionType::Script, None)],
+            classes: Vec::new(),
         }
     }
 
+    fn state(&self) -> &FunctionState {
+        self.states.last().unwrap()
+    }
+
+    fn state_mut(&mut self) -> &mut FunctionState {
+        self.states.last_mut().unwrap()
+    }
+
+    fn current_chunk(&mut self) -> &mut Chunk {
+        &mut self.state_mut().function.chunk
+    }
+
     fn advance(&mut self) {
         self.parser.advance();
     }
 
     fn emit_byte(&mut self, byte: u8) {
-        self.current_chunk.write_chunk(byte, self.parser.previous_line());
+        let span = self.parser.previous_span();
+        self.emit_byte_at(byte, span);
+    }
+
+    fn emit_byte_at(&mut self, byte: u8, span: Span) {
+        self.current_chunk().write_chunk(byte, span);
+    }
+
+    // operators are attributed to the operator token rather than the last operand
+    fn emit_operator(&mut self, ops: &[OpCode], span: Span) {
+        for &op in ops {
+            self.emit_byte_at(op as u8, span);
+        }
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_byte(OpCode::OP_LOOP as u8);
+
+        // +2 to also jump over the operand of OP_LOOP itself
+        let offset = self.current_chunk().code.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.parser.error("Loop body too large.");
+        }
+
+        let [high, low] = (offset as u16).to_be_bytes();
+        self.emit_bytes(high, low);
+    }
+
+    // emits a jump with a placeholder operand and returns the operand's offset
+    fn emit_jump(&mut self, instruction: OpCode) -> usize {
+        self.emit_byte(instruction as u8);
+        self.emit_bytes(0xff, 0xff);
+        self.current_chunk().code.len() - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 to adjust for the jump offset itself
+        let jump = self.current_chunk().code.len() - offset - 2;
+        if jump > u16::MAX as usize {
+            self.parser.error("Too much code to jump over.");
+        }
+
+        let [high, low] = (jump as u16).to_be_bytes();
+        self.current_chunk().code[offset] = high;
+        self.current_chunk().code[offset + 1] = low;
     }
 
     fn emit_return(&mut self) {
+        // initializers implicitly return the instance in slot zero
+        if self.state().function_type == FunctionType::Initializer {
+            self.emit_bytes(OpCode::OP_GET_LOCAL as u8, 0);
+        } else {
+            self.emit_byte(OpCode::OP_NIL as u8);
+        }
         self.emit_byte(OpCode::OP_RETURN as u8);
     }
 
-    fn end_compiler(&mut self) {
+    fn end_compiler(&mut self) -> (ObjFunction, Vec<Upvalue>) {
         self.emit_return();
+        let state = self.states.pop().unwrap();
+        let mut function = state.function;
+        function.upvalue_count = state.upvalues.len();
+        function.chunk.source = Rc::clone(&self.source);
+        if cfg!(feature = "debug_print_code") && !self.parser.had_error {
+            // debug output only, compiling goes on if the trace writer fails
+            let _ = function.chunk.disassemble(&mut self.vm.trace, &function.to_string());
+        }
+        (function, state.upvalues)
     }
 
     fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
@@ -39,73 +182,148 @@ impl Compiler {
         self.emit_byte(byte2);
     }
 
-    fn emit_constant(&mut self, value: f64) {
+    fn emit_constant(&mut self, value: Value) {
         let constant = self.make_constant(value);
-        self.emit_bytes(OpCode::OP_CONSTANT as u8, constant);
+        self.emit_indexed(OpCode::OP_CONSTANT, constant);
     }
 
-    fn make_constant(&mut self, value: f64) -> u8 {
-        let constant = self.current_chunk.add_constant(Value::Number(value));
-        if constant > u8::MAX as usize {
+    fn make_constant(&mut self, value: Value) -> usize {
+        self.current_chunk().add_constant(value)
+    }
+
+    // emits an instruction with a one-byte operand, or its long form when the
+    // operand is a constant index that needs more
+    fn emit_indexed(&mut self, op: OpCode, constant: usize) {
+        let span = self.parser.previous_span();
+        if !self.current_chunk().write_indexed(op, constant, span) {
             self.parser.error("Too many constants in one chunk.");
-            return 0;
         }
-        constant as u8
     }
 
-    fn grouping(&mut self) {
+    fn grouping(&mut self, _can_assign: bool) {
         self.expression();
         self.parser.consume(TokenType::RIGHT_PAREN, "Expect ')' after expression.");
     }
 
-    fn unary(&mut self) {
+    fn unary(&mut self, _can_assign: bool) {
         let op_type = self.parser.previous().token_type;
+        let span = self.parser.previous_span();
         self.parse_precedence(Precedence::Unary);
 
         match op_type {
-            TokenType::MINUS => self.emit_byte(OpCode::OP_NEGATE as u8),
+            TokenType::BANG => self.emit_operator(&[OpCode::OP_NOT], span),
+            TokenType::MINUS => self.emit_operator(&[OpCode::OP_NEGATE], span),
             _ => {}
         }
     }
 
-    fn get_rule(&mut self, token_type: TokenType) -> ParseRule {
+    fn and(&mut self, _can_assign: bool) {
+        let end_jump = self.emit_jump(OpCode::OP_JUMP_IF_FALSE);
+
+        self.emit_byte(OpCode::OP_POP as u8);
+        self.parse_precedence(Precedence::And);
+
+        self.patch_jump(end_jump);
+    }
+
+    fn or(&mut self, _can_assign: bool) {
+        let else_jump = self.emit_jump(OpCode::OP_JUMP_IF_FALSE);
+        let end_jump = self.emit_jump(OpCode::OP_JUMP);
+
+        self.patch_jump(else_jump);
+        self.emit_byte(OpCode::OP_POP as u8);
+
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump);
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: usize = 0;
+        if !self.parser.check(TokenType::RIGHT_PAREN) {
+            loop {
+                self.expression();
+                if arg_count == u8::MAX as usize {
+                    self.parser.error("Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+                if !self.parser.match_token(TokenType::COMMA) {
+                    break;
+                }
+            }
+        }
+        self.parser.consume(TokenType::RIGHT_PAREN, "Expect ')' after arguments.");
+        arg_count as u8
+    }
+
+    fn call(&mut self, _can_assign: bool) {
+        let arg_count = self.argument_list();
+        self.emit_bytes(OpCode::OP_CALL as u8, arg_count);
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.parser.consume(TokenType::IDENTIFIER, "Expect property name after '.'.");
+        let name = self.parser.previous().clone();
+        let name = self.identifier_constant(&name);
+
+        if can_assign && self.parser.match_token(TokenType::EQUAL) {
+            self.expression();
+            self.emit_indexed(OpCode::OP_SET_PROPERTY, name);
+        } else if self.parser.match_token(TokenType::LEFT_PAREN) {
+            let arg_count = self.argument_list();
+            self.emit_indexed(OpCode::OP_INVOKE, name);
+            self.emit_byte(arg_count);
+        } else {
+            self.emit_indexed(OpCode::OP_GET_PROPERTY, name);
+        }
+    }
+
+    fn literal(&mut self, _can_assign: bool) {
+        match self.parser.previous().token_type {
+            TokenType::FALSE => self.emit_byte(OpCode::OP_FALSE as u8),
+            TokenType::NIL => self.emit_byte(OpCode::OP_NIL as u8),
+            TokenType::TRUE => self.emit_byte(OpCode::OP_TRUE as u8),
+            _ => {}
+        }
+    }
+
+    fn get_rule(&mut self, token_type: TokenType) -> ParseRule<'a> {
         match token_type {
-            TokenType::LEFT_PAREN => ParseRule::new(Some(Compiler::grouping), None, Precedence::None),
+            TokenType::LEFT_PAREN => ParseRule::new(Some(Compiler::grouping), Some(Compiler::call), Precedence::Call),
             TokenType::RIGHT_PAREN => ParseRule::new(None, None, Precedence::None),
             TokenType::LEFT_BRACE => ParseRule::new(None, None, Precedence::None),
             TokenType::RIGHT_BRACE => ParseRule::new(None, None, Precedence::None),
             TokenType::COMMA => ParseRule::new(None, None, Precedence::None),
-            TokenType::DOT => ParseRule::new(None, None, Precedence::None),
+            TokenType::DOT => ParseRule::new(None, Some(Compiler::dot), Precedence::Call),
             TokenType::MINUS => ParseRule::new(Some(Compiler::unary), Some(Compiler::binary), Precedence::Term),
             TokenType::PLUS => ParseRule::new(None, Some(Compiler::binary), Precedence::Term),
             TokenType::SEMICOLON => ParseRule::new(None, None, Precedence::None),
             TokenType::SLASH => ParseRule::new(None, Some(Compiler::binary), Precedence::Factor),
             TokenType::STAR => ParseRule::new(None, Some(Compiler::binary), Precedence::Factor),
-            TokenType::BANG => ParseRule::new(None, None, Precedence::None),
-            TokenType::BANG_EQUAL => ParseRule::new(None, None, Precedence::None),
+            TokenType::BANG => ParseRule::new(Some(Compiler::unary), None, Precedence::None),
+            TokenType::BANG_EQUAL => ParseRule::new(None, Some(Compiler::binary), Precedence::Equality),
             TokenType::EQUAL => ParseRule::new(None, None, Precedence::None),
-            TokenType::EQUAL_EQUAL => ParseRule::new(None, None, Precedence::None),
-            TokenType::GREATER => ParseRule::new(None, None, Precedence::None),
-            TokenType::GREATER_EQUAL => ParseRule::new(None, None, Precedence::None),
-            TokenType::LESS => ParseRule::new(None, None, Precedence::None),
-            TokenType::LESS_EQUAL => ParseRule::new(None, None, Precedence::None),
-            TokenType::IDENTIFIER => ParseRule::new(None, None, Precedence::None),
-            TokenType::STRING => ParseRule::new(None, None, Precedence::None),
+            TokenType::EQUAL_EQUAL => ParseRule::new(None, Some(Compiler::binary), Precedence::Equality),
+            TokenType::GREATER => ParseRule::new(None, Some(Compiler::binary), Precedence::Comparison),
+            TokenType::GREATER_EQUAL => ParseRule::new(None, Some(Compiler::binary), Precedence::Comparison),
+            TokenType::LESS => ParseRule::new(None, Some(Compiler::binary), Precedence::Comparison),
+            TokenType::LESS_EQUAL => ParseRule::new(None, Some(Compiler::binary), Precedence::Comparison),
+            TokenType::IDENTIFIER => ParseRule::new(Some(Compiler::variable), None, Precedence::None),
+            TokenType::STRING => ParseRule::new(Some(Compiler::string), None, Precedence::None),
             TokenType::NUMBER => ParseRule::new(Some(Compiler::number), None, Precedence::None),
-            TokenType::AND => ParseRule::new(None, None, Precedence::None),
+            TokenType::AND => ParseRule::new(None, Some(Compiler::and), Precedence::And),
             TokenType::CLASS => ParseRule::new(None, None, Precedence::None),
             TokenType::ELSE => ParseRule::new(None, None, Precedence::None),
-            TokenType::FALSE => ParseRule::new(None, None, Precedence::None),
+            TokenType::FALSE => ParseRule::new(Some(Compiler::literal), None, Precedence::None),
             TokenType::FUN => ParseRule::new(None, None, Precedence::None),
             TokenType::FOR => ParseRule::new(None, None, Precedence::None),
             TokenType::IF => ParseRule::new(None, None, Precedence::None),
-            TokenType::NIL => ParseRule::new(None, None, Precedence::None),
-            TokenType::OR => ParseRule::new(None, None, Precedence::None),
+            TokenType::NIL => ParseRule::new(Some(Compiler::literal), None, Precedence::None),
+            TokenType::OR => ParseRule::new(None, Some(Compiler::or), Precedence::Or),
             TokenType::PRINT => ParseRule::new(None, None, Precedence::None),
             TokenType::RETURN => ParseRule::new(None, None, Precedence::None),
-            TokenType::SUPER => ParseRule::new(None, None, Precedence::None),
-            TokenType::THIS => ParseRule::new(None, None, Precedence::None),
-            TokenType::TRUE => ParseRule::new(None, None, Precedence::None),
+            TokenType::SUPER => ParseRule::new(Some(Compiler::super_), None, Precedence::None),
+            TokenType::THIS => ParseRule::new(Some(Compiler::this), None, Precedence::None),
+            TokenType::TRUE => ParseRule::new(Some(Compiler::literal), None, Precedence::None),
             TokenType::VAR => ParseRule::new(None, None, Precedence::None),
             TokenType::WHILE => ParseRule::new(None, None, Precedence::None),
             TokenType::EOF => ParseRule::new(None, None, Precedence::None),
@@ -113,16 +331,23 @@ impl Compiler {
         }
     }
 
-    fn binary(&mut self) {
+    fn binary(&mut self, _can_assign: bool) {
         let op_type = self.parser.previous().token_type;
+        let span = self.parser.previous_span();
         let rule = self.get_rule(op_type);
         self.parse_precedence(rule.precedence.next());
 
         match op_type {
-            TokenType::PLUS => self.emit_byte(OpCode::OP_ADD as u8),
-            TokenType::MINUS => self.emit_byte(OpCode::OP_SUBTRACT as u8),
-            TokenType::STAR => self.emit_byte(OpCode::OP_MULTIPLY as u8),
-            TokenType::SLASH => self.emit_byte(OpCode::OP_DIVIDE as u8),
+            TokenType::BANG_EQUAL => self.emit_operator(&[OpCode::OP_EQUAL, OpCode::OP_NOT], span),
+            TokenType::EQUAL_EQUAL => self.emit_operator(&[OpCode::OP_EQUAL], span),
+            TokenType::GREATER => self.emit_operator(&[OpCode::OP_GREATER], span),
+            TokenType::GREATER_EQUAL => self.emit_operator(&[OpCode::OP_LESS, OpCode::OP_NOT], span),
+            TokenType::LESS => self.emit_operator(&[OpCode::OP_LESS], span),
+            TokenType::LESS_EQUAL => self.emit_operator(&[OpCode::OP_GREATER, OpCode::OP_NOT], span),
+            TokenType::PLUS => self.emit_operator(&[OpCode::OP_ADD], span),
+            TokenType::MINUS => self.emit_operator(&[OpCode::OP_SUBTRACT], span),
+            TokenType::STAR => self.emit_operator(&[OpCode::OP_MULTIPLY], span),
+            TokenType::SLASH => self.emit_operator(&[OpCode::OP_DIVIDE], span),
             _ => {}
         }
     }
@@ -130,51 +355,538 @@ impl Compiler {
     fn parse_precedence(&mut self, precedence: Precedence) {
         self.advance();
         let prefix_rule = self.get_rule(self.parser.previous().token_type).prefix;
-        if let None = prefix_rule {
+        if prefix_rule.is_none() {
             self.parser.error("Expect expression.");
             return;
         }
 
-        prefix_rule.unwrap()(self);
+        let can_assign = precedence <= Precedence::Assignment;
+        prefix_rule.unwrap()(self, can_assign);
 
         while precedence <= self.get_rule(self.parser.current().token_type).precedence {
             self.advance();
             let infix_rule = self.get_rule(self.parser.previous().token_type).infix;
-            infix_rule.unwrap()(self);
+            infix_rule.unwrap()(self, can_assign);
+        }
+
+        if can_assign && self.parser.match_token(TokenType::EQUAL) {
+            self.parser.error("Invalid assignment target.");
+        }
+    }
+
+    fn identifier_constant(&mut self, name: &Token) -> usize {
+        let name = self.vm.heap.intern(&name.lexeme);
+        self.make_constant(Value::String(name))
+    }
+
+    fn resolve_local(&mut self, state_index: usize, name: &Token) -> Option<u8> {
+        let locals = &self.states[state_index].locals;
+        let index = locals.iter().rposition(|local| local.name.lexeme == name.lexeme)?;
+        if locals[index].depth.is_none() {
+            self.parser.error("Can't read local variable in its own initializer.");
+        }
+        Some(index as u8)
+    }
+
+    fn add_upvalue(&mut self, state_index: usize, index: u8, is_local: bool) -> u8 {
+        let upvalues = &self.states[state_index].upvalues;
+        if let Some(existing) = upvalues.iter().position(|upvalue| upvalue.index == index && upvalue.is_local == is_local) {
+            return existing as u8;
+        }
+
+        if upvalues.len() == UINT8_COUNT {
+            self.parser.error("Too many closure variables in function.");
+            return 0;
+        }
+
+        let upvalues = &mut self.states[state_index].upvalues;
+        upvalues.push(Upvalue { index, is_local });
+        (upvalues.len() - 1) as u8
+    }
+
+    // looks the name up in the enclosing functions, threading an upvalue through each one
+    fn resolve_upvalue(&mut self, state_index: usize, name: &Token) -> Option<u8> {
+        if state_index == 0 {
+            return None;
+        }
+
+        let enclosing = state_index - 1;
+        if let Some(local) = self.resolve_local(enclosing, name) {
+            self.states[enclosing].locals[local as usize].is_captured = true;
+            return Some(self.add_upvalue(state_index, local, true));
+        }
+
+        let upvalue = self.resolve_upvalue(enclosing, name)?;
+        Some(self.add_upvalue(state_index, upvalue, false))
+    }
+
+    fn add_local(&mut self, name: Token) {
+        if self.state().locals.len() == UINT8_COUNT {
+            self.parser.error("Too many local variables in function.");
+            return;
+        }
+        self.state_mut().locals.push(Local {
+            name,
+            depth: None,
+            is_captured: false,
+        });
+    }
+
+    fn declare_variable(&mut self) {
+        let scope_depth = self.state().scope_depth;
+        if scope_depth == 0 {
+            return;
+        }
+
+        let name = self.parser.previous().clone();
+        let previous = self.state().locals.iter().rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= scope_depth))
+            .find(|local| local.name.lexeme == name.lexeme)
+            .map(|local| local.name.span);
+        if let Some(span) = previous {
+            let note = format!("'{}' was first declared at line {}:{}.", name.lexeme, span.line, span.column);
+            self.parser.error_with_notes("Already a variable with this name in this scope.", vec![note]);
         }
+        self.add_local(name);
+    }
+
+    fn parse_variable(&mut self, message: &str) -> usize {
+        self.parser.consume(TokenType::IDENTIFIER, message);
+
+        self.declare_variable();
+        if self.state().scope_depth > 0 {
+            return 0;
+        }
+
+        let name = self.parser.previous().clone();
+        self.identifier_constant(&name)
+    }
+
+    fn mark_initialized(&mut self) {
+        let state = self.state_mut();
+        if state.scope_depth == 0 {
+            return;
+        }
+        if let Some(local) = state.locals.last_mut() {
+            local.depth = Some(state.scope_depth);
+        }
+    }
+
+    fn define_variable(&mut self, global: usize) {
+        if self.state().scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+        self.emit_indexed(OpCode::OP_DEFINE_GLOBAL, global);
     }
 
     fn expression(&mut self) {
         self.parse_precedence(Precedence::Assignment);
     }
 
-    fn number(&mut self) {
-        let value = self.parser.previous().lexeme.parse::<f64>().unwrap();
-        self.emit_constant(value);
+    fn block(&mut self) {
+        while !self.parser.check(TokenType::RIGHT_BRACE) && !self.parser.check(TokenType::EOF) {
+            self.declaration();
+        }
+        self.parser.consume(TokenType::RIGHT_BRACE, "Expect '}' after block.");
     }
 
-    pub fn compile(&mut self) -> bool {
-        self.advance();
+    fn function(&mut self, function_type: FunctionType) {
+        let name = self.vm.heap.intern(&self.parser.previous().lexeme);
+        self.states.push(FunctionState::new(function_type, Some(name)));
+        self.begin_scope();
+
+        self.parser.consume(TokenType::LEFT_PAREN, "Expect '(' after function name.");
+        if !self.parser.check(TokenType::RIGHT_PAREN) {
+            loop {
+                self.state_mut().function.arity += 1;
+                if self.state().function.arity > u8::MAX as usize {
+                    self.parser.error_at_current("Can't have more than 255 parameters.");
+                }
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+                if !self.parser.match_token(TokenType::COMMA) {
+                    break;
+                }
+            }
+        }
+        self.parser.consume(TokenType::RIGHT_PAREN, "Expect ')' after parameters.");
+        self.parser.consume(TokenType::LEFT_BRACE, "Expect '{' before function body.");
+        self.block();
+
+        // no end_scope, the whole frame is discarded on return
+        let (function, upvalues) = self.end_compiler();
+        let function = self.vm.heap.alloc(function);
+        let constant = self.make_constant(Value::Function(function));
+        self.emit_indexed(OpCode::OP_CLOSURE, constant);
+
+        for upvalue in upvalues {
+            self.emit_bytes(upvalue.is_local as u8, upvalue.index);
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.state_mut().scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.state_mut().scope_depth -= 1;
+
+        while let Some(local) = self.state().locals.last() {
+            if local.depth.is_some_and(|depth| depth <= self.state().scope_depth) {
+                break;
+            }
+            if local.is_captured {
+                self.emit_byte(OpCode::OP_CLOSE_UPVALUE as u8);
+            } else {
+                self.emit_byte(OpCode::OP_POP as u8);
+            }
+            self.state_mut().locals.pop();
+        }
+    }
+
+    fn expression_statement(&mut self) {
         self.expression();
-        self.parser.consume(TokenType::EOF, "Expect end of expression.");
-        self.end_compiler();
-        !self.parser.had_error
+        self.parser.consume(TokenType::SEMICOLON, "Expect ';' after expression.");
+        self.emit_byte(OpCode::OP_POP as u8);
+    }
+
+    fn for_statement(&mut self) {
+        self.begin_scope();
+        self.parser.consume(TokenType::LEFT_PAREN, "Expect '(' after 'for'.");
+        if self.parser.match_token(TokenType::SEMICOLON) {
+            // no initializer
+        } else if self.parser.match_token(TokenType::VAR) {
+            self.var_declaration();
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.current_chunk().code.len();
+        let mut exit_jump = None;
+        if !self.parser.match_token(TokenType::SEMICOLON) {
+            self.expression();
+            self.parser.consume(TokenType::SEMICOLON, "Expect ';' after loop condition.");
+
+            // jump out of the loop if the condition is false
+            exit_jump = Some(self.emit_jump(OpCode::OP_JUMP_IF_FALSE));
+            self.emit_byte(OpCode::OP_POP as u8);
+        }
+
+        if !self.parser.match_token(TokenType::RIGHT_PAREN) {
+            // the increment runs after the body, so jump over it and loop back
+            let body_jump = self.emit_jump(OpCode::OP_JUMP);
+            let increment_start = self.current_chunk().code.len();
+            self.expression();
+            self.emit_byte(OpCode::OP_POP as u8);
+            self.parser.consume(TokenType::RIGHT_PAREN, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_byte(OpCode::OP_POP as u8);
+        }
+
+        self.end_scope();
     }
 
-    pub fn get_chunk(&mut self) -> Chunk {
-        self.current_chunk.clone()
+    fn if_statement(&mut self) {
+        self.parser.consume(TokenType::LEFT_PAREN, "Expect '(' after 'if'.");
+        self.expression();
+        self.parser.consume(TokenType::RIGHT_PAREN, "Expect ')' after condition.");
+
+        let then_jump = self.emit_jump(OpCode::OP_JUMP_IF_FALSE);
+        self.emit_byte(OpCode::OP_POP as u8);
+        self.statement();
+
+        let else_jump = self.emit_jump(OpCode::OP_JUMP);
+
+        self.patch_jump(then_jump);
+        self.emit_byte(OpCode::OP_POP as u8);
+
+        if self.parser.match_token(TokenType::ELSE) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn method(&mut self) {
+        self.parser.consume(TokenType::IDENTIFIER, "Expect method name.");
+        let name = self.parser.previous().clone();
+        let constant = self.identifier_constant(&name);
+
+        let function_type = if name.lexeme == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(function_type);
+        self.emit_indexed(OpCode::OP_METHOD, constant);
+    }
+
+    fn class_declaration(&mut self) {
+        self.parser.consume(TokenType::IDENTIFIER, "Expect class name.");
+        let class_name = self.parser.previous().clone();
+        let name_constant = self.identifier_constant(&class_name);
+        self.declare_variable();
+
+        self.emit_indexed(OpCode::OP_CLASS, name_constant);
+        self.define_variable(name_constant);
+
+        self.classes.push(ClassState { has_superclass: false });
+
+        if self.parser.match_token(TokenType::LESS) {
+            self.parser.consume(TokenType::IDENTIFIER, "Expect superclass name.");
+            self.variable(false);
+
+            if class_name.lexeme == self.parser.previous().lexeme {
+                self.parser.error("A class can't inherit from itself.");
+            }
+
+            // the superclass lives in a local named 'super' that methods capture
+            self.begin_scope();
+            self.add_local(self.synthetic_token("super"));
+            self.define_variable(0);
+
+            self.named_variable(&class_name, false);
+            self.emit_byte(OpCode::OP_INHERIT as u8);
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+
+        // load the class back onto the stack so methods can be bound to it
+        self.named_variable(&class_name, false);
+        self.parser.consume(TokenType::LEFT_BRACE, "Expect '{' before class body.");
+        while !self.parser.check(TokenType::RIGHT_BRACE) && !self.parser.check(TokenType::EOF) {
+            self.method();
+        }
+        self.parser.consume(TokenType::RIGHT_BRACE, "Expect '}' after class body.");
+        self.emit_byte(OpCode::OP_POP as u8);
+
+        if self.classes.last().unwrap().has_superclass {
+            self.end_scope();
+        }
+        self.classes.pop();
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        // a function may refer to itself, so it is initialized before its body
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.parser.consume(TokenType::SEMICOLON, "Expect ';' after value.");
+        self.emit_byte(OpCode::OP_PRINT as u8);
+    }
+
+    fn return_statement(&mut self) {
+        if self.state().function_type == FunctionType::Script {
+            self.parser.error("Can't return from top-level code.");
+        }
+
+        if self.parser.match_token(TokenType::SEMICOLON) {
+            self.emit_return();
+        } else {
+            if self.state().function_type == FunctionType::Initializer {
+                self.parser.error("Can't return a value from an initializer.");
+            }
+
+            self.expression();
+            self.parser.consume(TokenType::SEMICOLON, "Expect ';' after return value.");
+            self.emit_byte(OpCode::OP_RETURN as u8);
+        }
+    }
+
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expect variable name.");
+
+        if self.parser.match_token(TokenType::EQUAL) {
+            self.expression();
+        } else {
+            self.emit_byte(OpCode::OP_NIL as u8);
+        }
+        self.parser.consume(TokenType::SEMICOLON, "Expect ';' after variable declaration.");
+
+        self.define_variable(global);
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.current_chunk().code.len();
+        self.parser.consume(TokenType::LEFT_PAREN, "Expect '(' after 'while'.");
+        self.expression();
+        self.parser.consume(TokenType::RIGHT_PAREN, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::OP_JUMP_IF_FALSE);
+        self.emit_byte(OpCode::OP_POP as u8);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::OP_POP as u8);
+    }
+
+    // functions being compiled are not on the heap yet, so their constants are roots
+    fn collect_garbage(&mut self) {
+        for state in &self.states {
+            if let Some(name) = state.function.name {
+                self.vm.heap.mark_object(name);
+            }
+            for &constant in &state.function.chunk.values.data {
+                self.vm.heap.mark_value(constant);
+            }
+        }
+        self.vm.collect_garbage();
+    }
+
+    fn declaration(&mut self) {
+        if self.vm.heap.should_collect() {
+            self.collect_garbage();
+        }
+
+        if self.parser.match_token(TokenType::CLASS) {
+            self.class_declaration();
+        } else if self.parser.match_token(TokenType::FUN) {
+            self.fun_declaration();
+        } else if self.parser.match_token(TokenType::VAR) {
+            self.var_declaration();
+        } else {
+            self.statement();
+        }
+
+        if self.parser.panic_mode {
+            self.parser.synchronize();
+        }
+    }
+
+    fn statement(&mut self) {
+        if self.parser.match_token(TokenType::PRINT) {
+            self.print_statement();
+        } else if self.parser.match_token(TokenType::FOR) {
+            self.for_statement();
+        } else if self.parser.match_token(TokenType::IF) {
+            self.if_statement();
+        } else if self.parser.match_token(TokenType::RETURN) {
+            self.return_statement();
+        } else if self.parser.match_token(TokenType::WHILE) {
+            self.while_statement();
+        } else if self.parser.match_token(TokenType::LEFT_BRACE) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement();
+        }
+    }
+
+    fn number(&mut self, _can_assign: bool) {
+        let value = self.parser.previous().lexeme.parse::<f64>().unwrap();
+        self.emit_constant(Value::Number(value));
+    }
+
+    fn named_variable(&mut self, name: &Token, can_assign: bool) {
+        let current = self.states.len() - 1;
+        let (get_op, set_op, arg) = if let Some(slot) = self.resolve_local(current, name) {
+            (OpCode::OP_GET_LOCAL, OpCode::OP_SET_LOCAL, slot as usize)
+        } else if let Some(upvalue) = self.resolve_upvalue(current, name) {
+            (OpCode::OP_GET_UPVALUE, OpCode::OP_SET_UPVALUE, upvalue as usize)
+        } else {
+            let constant = self.identifier_constant(name);
+            (OpCode::OP_GET_GLOBAL, OpCode::OP_SET_GLOBAL, constant)
+        };
+
+        if can_assign && self.parser.match_token(TokenType::EQUAL) {
+            self.expression();
+            self.emit_indexed(set_op, arg);
+        } else {
+            self.emit_indexed(get_op, arg);
+        }
+    }
+
+    fn variable(&mut self, can_assign: bool) {
+        let name = self.parser.previous().clone();
+        self.named_variable(&name, can_assign);
+    }
+
+    fn synthetic_token(&self, text: &str) -> Token {
+        Token::new(TokenType::IDENTIFIER, text.to_string(), self.parser.previous_span())
+    }
+
+    fn super_(&mut self, _can_assign: bool) {
+        match self.classes.last() {
+            None => self.parser.error("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                self.parser.error("Can't use 'super' in a class with no superclass.")
+            }
+            Some(_) => {}
+        }
+
+        self.parser.consume(TokenType::DOT, "Expect '.' after 'super'.");
+        self.parser.consume(TokenType::IDENTIFIER, "Expect superclass method name.");
+        let name = self.parser.previous().clone();
+        let name = self.identifier_constant(&name);
+
+        self.named_variable(&self.synthetic_token("this"), false);
+        if self.parser.match_token(TokenType::LEFT_PAREN) {
+            let arg_count = self.argument_list();
+            self.named_variable(&self.synthetic_token("super"), false);
+            self.emit_indexed(OpCode::OP_SUPER_INVOKE, name);
+            self.emit_byte(arg_count);
+        } else {
+            self.named_variable(&self.synthetic_token("super"), false);
+            self.emit_indexed(OpCode::OP_GET_SUPER, name);
+        }
+    }
+
+    fn this(&mut self, _can_assign: bool) {
+        if self.classes.is_empty() {
+            self.parser.error("Can't use 'this' outside of a class.");
+            return;
+        }
+        // 'this' can never be assigned to
+        self.variable(false);
+    }
+
+    fn string(&mut self, _can_assign: bool) {
+        // strip the surrounding quotes
+        let lexeme = &self.parser.previous().lexeme;
+        let value = Value::String(self.vm.heap.intern(&lexeme[1..lexeme.len() - 1]));
+        self.emit_constant(value);
+    }
+
+    pub fn compile(mut self) -> Result<ObjFunction, Vec<Diagnostic>> {
+        self.advance();
+        while !self.parser.match_token(TokenType::EOF) {
+            self.declaration();
+        }
+        let (function, _) = self.end_compiler();
+        if self.parser.had_error {
+            return Err(self.parser.diagnostics);
+        }
+        Ok(function)
     }
 }
 
 
-struct ParseRule {
-    prefix: Option<fn(&mut Compiler)>,
-    infix: Option<fn(&mut Compiler)>,
+type ParseFn<'a> = fn(&mut Compiler<'a>, bool);
+
+struct ParseRule<'a> {
+    prefix: Option<ParseFn<'a>>,
+    infix: Option<ParseFn<'a>>,
     precedence: Precedence,
 }
 
-impl ParseRule {
-    fn new(prefix: Option<fn(&mut Compiler)>, infix: Option<fn(&mut Compiler)>, precedence: Precedence) -> Self {
+impl<'a> ParseRule<'a> {
+    fn new(prefix: Option<ParseFn<'a>>, infix: Option<ParseFn<'a>>, precedence: Precedence) -> Self {
         Self {
             prefix,
             infix,
@@ -183,3 +895,38 @@ impl ParseRule {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identical_string_literals_share_storage() {
+        let mut vm = VM::new();
+        let compiler = Compiler::new(Rc::new("\"lox\" == \"lox\";".to_string()), &mut vm);
+        let function = compiler.compile().expect("source should compile");
+
+        let constants = &function.chunk.values.data;
+        match (constants[0], constants[1]) {
+            (Value::String(a), Value::String(b)) => assert!(a == b),
+            _ => panic!("expected two string constants"),
+        }
+    }
+
+    #[test]
+    fn every_statement_error_is_reported() {
+        let mut vm = VM::new();
+        let source = "var = 1;\nprint 1 +;\nvar ok = 2;\nfun f( { }\nclass {}";
+        let errors = match Compiler::new(Rc::new(source.to_string()), &mut vm).compile() {
+            Ok(_) => panic!("source should not compile"),
+            Err(errors) => errors,
+        };
+        let reported: Vec<(usize, &str)> = errors.iter().map(|error| (error.span.line, error.message.as_str())).collect();
+        assert_eq!(reported, vec![
+            (1, "Expect variable name."),
+            (2, "Expect expression."),
+            (4, "Expect parameter name."),
+            (5, "Expect class name."),
+        ]);
+    }
+}
@@ -0,0 +1,131 @@
+use std::fmt::Write;
+
+use crate::token::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    // nothing reports warnings yet, embedders may build their own
+    Warning,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Severity::Error => write!(f, "Error"),
+            Severity::Warning => write!(f, "Warning"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DiagnosticStyle {
+    // the offending source line with a caret under the span
+    #[default]
+    Rich,
+    // the single `[line N] Error at 'x': msg` line
+    Plain,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub span: Span,
+    pub message: String,
+    pub notes: Vec<String>,
+    // where the problem is in words, like "at end", for plain output
+    pub location: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn error(span: Span, message: &str) -> Self {
+        Self {
+            severity: Severity::Error,
+            span,
+            message: message.to_string(),
+            notes: Vec::new(),
+            location: None,
+        }
+    }
+
+    pub fn at(mut self, location: String) -> Self {
+        self.location = Some(location);
+        self
+    }
+
+    pub fn with_note(mut self, note: String) -> Self {
+        self.notes.push(note);
+        self
+    }
+
+    pub fn render(&self, source: &str, style: DiagnosticStyle) -> String {
+        match style {
+            DiagnosticStyle::Plain => self.render_plain(),
+            DiagnosticStyle::Rich => self.render_rich(source),
+        }
+    }
+
+    fn render_plain(&self) -> String {
+        let mut out = format!("[line {}] {}", self.span.line, self.severity);
+        if let Some(location) = &self.location {
+            write!(out, " {}", location).unwrap();
+        }
+        // notes are left out so every diagnostic stays on one line
+        writeln!(out, ": {}", self.message).unwrap();
+        out
+    }
+
+    fn render_rich(&self, source: &str) -> String {
+        let start = self.span.start.min(source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..].find('\n').map_or(source.len(), |i| start + i);
+        let text = &source[line_start..line_end];
+
+        let gutter = " ".repeat(self.span.line.to_string().len());
+        // spans are byte offsets but the caret lines up by characters
+        let indent = source[line_start..start].chars().count();
+        let end = self.span.end.clamp(start, line_end);
+        let underline = source[start..end].chars().count().max(1);
+
+        let mut out = String::new();
+        writeln!(out, "{}: {}", self.severity.to_string().to_lowercase(), self.message).unwrap();
+        writeln!(out, "{}--> line {}:{}", gutter, self.span.line, self.span.column).unwrap();
+        writeln!(out, "{} |", gutter).unwrap();
+        writeln!(out, "{} | {}", self.span.line, text).unwrap();
+        writeln!(out, "{} | {}{}", gutter, " ".repeat(indent), "^".repeat(underline)).unwrap();
+        for note in &self.notes {
+            writeln!(out, "{} = note: {}", gutter, note).unwrap();
+        }
+        out
+    }
+}
+
+// the plain form, which needs no source text
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.render_plain())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rich_output_underlines_the_span() {
+        let source = "var a = 1;\nprint a +;\n";
+        let diagnostic = Diagnostic::error(Span::new(20, 21, 2, 10), "Expect expression.").at("at ';'".to_string());
+        assert_eq!(
+            diagnostic.render(source, DiagnosticStyle::Rich),
+            "error: Expect expression.\n \
+             --> line 2:10\n  \
+             |\n\
+             2 | print a +;\n  \
+             |          ^\n"
+        );
+        assert_eq!(
+            diagnostic.render(source, DiagnosticStyle::Plain),
+            "[line 2] Error at ';': Expect expression.\n"
+        );
+    }
+}
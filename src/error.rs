@@ -0,0 +1,42 @@
+use crate::diagnostic::Diagnostic;
+use crate::vm::RuntimeError;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoxError {
+    // every error the compiler found, in source order
+    Compile(Vec<Diagnostic>),
+    Runtime(RuntimeError),
+}
+
+impl LoxError {
+    // the sysexits codes used by the command line interpreter
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            LoxError::Compile(_) => 65,
+            LoxError::Runtime(_) => 70,
+        }
+    }
+}
+
+impl std::fmt::Display for LoxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LoxError::Compile(diagnostics) => {
+                for diagnostic in diagnostics {
+                    write!(f, "{}", diagnostic)?;
+                }
+                Ok(())
+            }
+            LoxError::Runtime(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for LoxError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoxError::Compile(_) => None,
+            LoxError::Runtime(error) => Some(error),
+        }
+    }
+}
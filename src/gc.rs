@@ -0,0 +1,218 @@
+use std::cell::Cell;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::ops::Deref;
+use std::ptr::NonNull;
+
+use crate::interner::Interner;
+use crate::object::ObjString;
+use crate::value::Value;
+
+const GC_HEAP_GROW_FACTOR: usize = 2;
+const FIRST_GC: usize = 1024 * 1024;
+
+// Implemented by every heap object so the collector can find the objects it
+// references.
+pub trait Trace {
+    fn trace(&self, heap: &mut Heap);
+
+    // bytes owned by the object outside of its own allocation
+    fn extra_size(&self) -> usize {
+        0
+    }
+}
+
+struct GcBox<T: ?Sized> {
+    marked: Cell<bool>,
+    // grows with the object so the sweep gives back everything it charged
+    size: Cell<usize>,
+    value: T,
+}
+
+// A handle to an object owned by the Heap. Handles are only valid while the
+// object is reachable from a root, the collector frees everything else.
+pub struct Gc<T: 'static> {
+    ptr: NonNull<GcBox<T>>,
+}
+
+impl<T> Gc<T> {
+    fn header(&self) -> &GcBox<T> {
+        // the heap keeps the box alive for as long as the handle is reachable
+        unsafe { self.ptr.as_ref() }
+    }
+
+    pub fn is_marked(&self) -> bool {
+        self.header().marked.get()
+    }
+}
+
+impl<T> Clone for Gc<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Gc<T> {}
+
+impl<T> Deref for Gc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.header().value
+    }
+}
+
+impl<T: std::fmt::Display> std::fmt::Display for Gc<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+// handles compare and hash by identity
+impl<T> PartialEq for Gc<T> {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::addr_eq(self.ptr.as_ptr(), other.ptr.as_ptr())
+    }
+}
+
+impl<T> Eq for Gc<T> {}
+
+impl<T> Hash for Gc<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.ptr.as_ptr().cast::<()>().hash(state);
+    }
+}
+
+pub struct Heap {
+    objects: Vec<NonNull<GcBox<dyn Trace>>>,
+    // marked objects whose references have not been traced yet
+    gray_stack: Vec<NonNull<GcBox<dyn Trace>>>,
+    pub bytes_allocated: usize,
+    next_gc: usize,
+    strings: Interner,
+    // collect before every instruction to shake out missing roots
+    pub stress: bool,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self {
+            objects: Vec::new(),
+            gray_stack: Vec::new(),
+            bytes_allocated: 0,
+            next_gc: FIRST_GC,
+            strings: Interner::new(),
+            stress: cfg!(feature = "debug_stress_gc"),
+        }
+    }
+
+    pub fn alloc<T: Trace + 'static>(&mut self, value: T) -> Gc<T> {
+        let size = std::mem::size_of::<GcBox<T>>() + value.extra_size();
+        let boxed = Box::new(GcBox {
+            marked: Cell::new(false),
+            size: Cell::new(size),
+            value,
+        });
+        let ptr = NonNull::from(Box::leak(boxed));
+        self.objects.push(ptr);
+        self.bytes_allocated += size;
+        Gc { ptr }
+    }
+
+    // Charges memory an object gained after it was allocated, like new
+    // entries in a field or method table.
+    pub fn grow<T>(&mut self, object: Gc<T>, bytes: usize) {
+        let header = object.header();
+        header.size.set(header.size.get() + bytes);
+        self.bytes_allocated += bytes;
+    }
+
+    pub fn intern(&mut self, chars: &str) -> Gc<ObjString> {
+        if let Some(string) = self.strings.get(chars) {
+            return string;
+        }
+        let string = self.alloc(ObjString::new(chars));
+        self.strings.insert(string);
+        string
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.stress || self.bytes_allocated > self.next_gc
+    }
+
+    pub fn mark_object<T: Trace + 'static>(&mut self, object: Gc<T>) {
+        if object.is_marked() {
+            return;
+        }
+        object.header().marked.set(true);
+        self.gray_stack.push(object.ptr);
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        match value {
+            Value::Bool(_) | Value::Nil | Value::Number(_) => {}
+            Value::String(string) => self.mark_object(string),
+            Value::Function(function) => self.mark_object(function),
+            Value::Closure(closure) => self.mark_object(closure),
+            Value::Class(class) => self.mark_object(class),
+            Value::Instance(instance) => self.mark_object(instance),
+            Value::BoundMethod(bound) => self.mark_object(bound),
+            Value::Native(native) => self.mark_object(native),
+        }
+    }
+
+    // Frees every object that is not reachable from the roots marked so far,
+    // logging to trace when debug_log_gc is on.
+    pub fn collect(&mut self, trace: &mut dyn Write) {
+        let before = self.bytes_allocated;
+
+        self.trace_references();
+        self.strings.remove_unmarked();
+        self.sweep();
+
+        self.next_gc = self.bytes_allocated * GC_HEAP_GROW_FACTOR;
+
+        if cfg!(feature = "debug_log_gc") {
+            // a failed log line is no reason to abandon the collection
+            let _ = writeln!(
+                trace,
+                "-- gc collected {} bytes (from {} to {}) next at {}",
+                before - self.bytes_allocated,
+                before,
+                self.bytes_allocated,
+                self.next_gc
+            );
+        }
+    }
+
+    fn trace_references(&mut self) {
+        while let Some(ptr) = self.gray_stack.pop() {
+            // gray objects are still owned by the heap
+            let object = unsafe { ptr.as_ref() };
+            object.value.trace(self);
+        }
+    }
+
+    fn sweep(&mut self) {
+        let bytes_allocated = &mut self.bytes_allocated;
+        self.objects.retain(|ptr| {
+            let object = unsafe { ptr.as_ref() };
+            if object.marked.get() {
+                object.marked.set(false);
+                return true;
+            }
+            *bytes_allocated -= object.size.get();
+            // unreachable, so no handle to the box can be used again
+            unsafe { drop(Box::from_raw(ptr.as_ptr())) };
+            false
+        });
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        for ptr in self.objects.drain(..) {
+            unsafe { drop(Box::from_raw(ptr.as_ptr())) };
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::gc::Gc;
+use crate::object::ObjString;
+
+// Every string value is interned so that string equality is a pointer
+// comparison. The table does not keep strings alive, unmarked entries are
+// dropped before the collector sweeps.
+pub struct Interner {
+    strings: HashMap<Rc<str>, Gc<ObjString>>,
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Self {
+            strings: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, chars: &str) -> Option<Gc<ObjString>> {
+        self.strings.get(chars).copied()
+    }
+
+    pub fn insert(&mut self, string: Gc<ObjString>) {
+        self.strings.insert(string.chars.clone(), string);
+    }
+
+    pub fn remove_unmarked(&mut self) {
+        self.strings.retain(|_, string| string.is_marked());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gc::Heap;
+
+    #[test]
+    fn interning_equal_strings_returns_the_same_allocation() {
+        let mut heap = Heap::new();
+        let literal = heap.intern("lox");
+        let concatenated = heap.intern(&format!("{}{}", "lo", "x"));
+        assert!(literal == concatenated);
+    }
+}
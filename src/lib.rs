@@ -0,0 +1,20 @@
+mod chunk;
+mod value;
+mod object;
+mod opcode;
+mod vm;
+mod compiler;
+mod scanner;
+mod token;
+mod parser;
+mod interner;
+mod gc;
+mod diagnostic;
+mod error;
+
+pub use vm::{RuntimeError, TraceFrame, VMBuilder, VMConfig, VM};
+pub use value::LoxValue;
+pub use error::LoxError;
+pub use object::NativeFn;
+pub use diagnostic::{Diagnostic, DiagnosticStyle, Severity};
+pub use token::Span;
@@ -1,15 +1,6 @@
-mod chunk;
-mod value;
-mod opcode;
-mod vm;
-mod compiler;
-mod scanner;
-mod token;
-mod parser;
-
 use std::io::Write;
 
-use vm::{VM, InterpretResult};
+use rlox::{DiagnosticStyle, VM};
 
 fn repl(vm: &mut VM) {
     loop {
@@ -17,36 +8,36 @@ fn repl(vm: &mut VM) {
         std::io::stdout().flush().expect("Error flushing stdout");
         let mut input = String::new();
         std::io::stdin().read_line(&mut input).expect("Error reading line");
-        let res = vm.interpret(input);
-        match res {
-            InterpretResult::CompileError => std::process::exit(65),
-            InterpretResult::RuntimeError => std::process::exit(70),
-            _ => (),
+        if let Err(error) = vm.interpret(input) {
+            std::process::exit(error.exit_code());
         }
     }
 }
 
 fn run_file(vm: &mut VM, path: &str) {
     let source = std::fs::read_to_string(path).expect("Error reading file");
-    let res = vm.interpret(source);
-    match res {
-        InterpretResult::CompileError => std::process::exit(65),
-        InterpretResult::RuntimeError => std::process::exit(70),
-        _ => (),
+    if let Err(error) = vm.interpret(source) {
+        std::process::exit(error.exit_code());
     }
 }
 
 fn main() {
-    let mut vm = VM::new();
+    let mut builder = VM::builder();
 
-    let args: Vec<String> = std::env::args().collect();
+    let mut args: Vec<String> = std::env::args().collect();
+    // keeps the single-line error format for tools that parse it
+    if let Some(index) = args.iter().position(|arg| arg == "--plain-diagnostics") {
+        args.remove(index);
+        builder = builder.diagnostic_style(DiagnosticStyle::Plain);
+    }
+    let mut vm = builder.build();
 
     if args.len() == 1 {
         repl(&mut vm);
     } else if args.len() == 2 {
         run_file(&mut vm, &args[1]);
     } else {
-        eprintln!("Usage: rlox [path]");
+        eprintln!("Usage: rlox [--plain-diagnostics] [path]");
         std::process::exit(64);
     }
 }
@@ -0,0 +1,217 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::chunk::Chunk;
+use crate::gc::{Gc, Heap, Trace};
+use crate::value::{LoxValue, Value};
+
+pub struct ObjString {
+    // shared with the interning table's key
+    pub chars: Rc<str>,
+}
+
+impl ObjString {
+    pub fn new(chars: &str) -> Self {
+        Self {
+            chars: Rc::from(chars),
+        }
+    }
+}
+
+impl Trace for ObjString {
+    fn trace(&self, _heap: &mut Heap) {}
+
+    fn extra_size(&self) -> usize {
+        self.chars.len()
+    }
+}
+
+impl std::fmt::Display for ObjString {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.chars)
+    }
+}
+
+pub struct ObjFunction {
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    // None for the top-level script
+    pub name: Option<Gc<ObjString>>,
+}
+
+impl ObjFunction {
+    pub fn new(name: Option<Gc<ObjString>>) -> Self {
+        Self {
+            arity: 0,
+            upvalue_count: 0,
+            chunk: Chunk::new(),
+            name,
+        }
+    }
+}
+
+impl Trace for ObjFunction {
+    fn trace(&self, heap: &mut Heap) {
+        if let Some(name) = self.name {
+            heap.mark_object(name);
+        }
+        for &constant in &self.chunk.values.data {
+            heap.mark_value(constant);
+        }
+    }
+
+    fn extra_size(&self) -> usize {
+        self.chunk.heap_size()
+    }
+}
+
+impl std::fmt::Display for ObjFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {}>", name),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+pub struct ObjClosure {
+    pub function: Gc<ObjFunction>,
+    pub upvalues: Vec<Gc<ObjUpvalue>>,
+}
+
+impl ObjClosure {
+    pub fn new(function: Gc<ObjFunction>, upvalues: Vec<Gc<ObjUpvalue>>) -> Self {
+        Self { function, upvalues }
+    }
+}
+
+impl Trace for ObjClosure {
+    fn trace(&self, heap: &mut Heap) {
+        heap.mark_object(self.function);
+        for &upvalue in &self.upvalues {
+            heap.mark_object(upvalue);
+        }
+    }
+
+    fn extra_size(&self) -> usize {
+        self.upvalues.len() * std::mem::size_of::<Gc<ObjUpvalue>>()
+    }
+}
+
+pub enum UpvalueState {
+    // the captured variable still lives on the stack at this slot
+    Open(usize),
+    // the variable went out of scope and was moved into the upvalue
+    Closed(Value),
+}
+
+pub struct ObjUpvalue {
+    pub state: RefCell<UpvalueState>,
+}
+
+impl ObjUpvalue {
+    pub fn new(slot: usize) -> Self {
+        Self {
+            state: RefCell::new(UpvalueState::Open(slot)),
+        }
+    }
+}
+
+impl Trace for ObjUpvalue {
+    fn trace(&self, heap: &mut Heap) {
+        // open upvalues are reached through the stack
+        if let UpvalueState::Closed(value) = *self.state.borrow() {
+            heap.mark_value(value);
+        }
+    }
+}
+
+pub struct ObjClass {
+    pub name: Gc<ObjString>,
+    pub methods: RefCell<HashMap<Gc<ObjString>, Gc<ObjClosure>>>,
+}
+
+impl ObjClass {
+    pub fn new(name: Gc<ObjString>) -> Self {
+        Self {
+            name,
+            methods: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+impl Trace for ObjClass {
+    fn trace(&self, heap: &mut Heap) {
+        heap.mark_object(self.name);
+        for (&name, &method) in self.methods.borrow().iter() {
+            heap.mark_object(name);
+            heap.mark_object(method);
+        }
+    }
+}
+
+pub struct ObjInstance {
+    pub class: Gc<ObjClass>,
+    pub fields: RefCell<HashMap<Gc<ObjString>, Value>>,
+}
+
+impl ObjInstance {
+    pub fn new(class: Gc<ObjClass>) -> Self {
+        Self {
+            class,
+            fields: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+impl Trace for ObjInstance {
+    fn trace(&self, heap: &mut Heap) {
+        heap.mark_object(self.class);
+        for (&name, &value) in self.fields.borrow().iter() {
+            heap.mark_object(name);
+            heap.mark_value(value);
+        }
+    }
+}
+
+// a function implemented in rust, errors become runtime errors
+pub type NativeFn = fn(&[LoxValue]) -> Result<LoxValue, String>;
+
+pub struct ObjNative {
+    pub name: Gc<ObjString>,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+impl ObjNative {
+    pub fn new(name: Gc<ObjString>, arity: usize, function: NativeFn) -> Self {
+        Self { name, arity, function }
+    }
+}
+
+impl Trace for ObjNative {
+    fn trace(&self, heap: &mut Heap) {
+        heap.mark_object(self.name);
+    }
+}
+
+// a method closure bound to the instance it was accessed on
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub method: Gc<ObjClosure>,
+}
+
+impl ObjBoundMethod {
+    pub fn new(receiver: Value, method: Gc<ObjClosure>) -> Self {
+        Self { receiver, method }
+    }
+}
+
+impl Trace for ObjBoundMethod {
+    fn trace(&self, heap: &mut Heap) {
+        heap.mark_value(self.receiver);
+        heap.mark_object(self.method);
+    }
+}
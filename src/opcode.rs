@@ -1,3 +1,4 @@
+#[allow(non_camel_case_types)]
 #[repr(u8)]
 #[derive(Debug, Clone, Copy)]
 pub enum OpCode {
@@ -8,6 +9,48 @@ pub enum OpCode {
     OP_DIVIDE,
     OP_NEGATE,
     OP_RETURN,
+    OP_NIL,
+    OP_TRUE,
+    OP_FALSE,
+    OP_NOT,
+    OP_EQUAL,
+    OP_GREATER,
+    OP_LESS,
+    OP_PRINT,
+    OP_POP,
+    OP_DEFINE_GLOBAL,
+    OP_GET_GLOBAL,
+    OP_SET_GLOBAL,
+    OP_GET_LOCAL,
+    OP_SET_LOCAL,
+    OP_JUMP,
+    OP_JUMP_IF_FALSE,
+    OP_LOOP,
+    OP_CALL,
+    OP_CLOSURE,
+    OP_GET_UPVALUE,
+    OP_SET_UPVALUE,
+    OP_CLOSE_UPVALUE,
+    OP_CLASS,
+    OP_GET_PROPERTY,
+    OP_SET_PROPERTY,
+    OP_METHOD,
+    OP_INVOKE,
+    OP_INHERIT,
+    OP_GET_SUPER,
+    OP_SUPER_INVOKE,
+    OP_CONSTANT_LONG,
+    OP_DEFINE_GLOBAL_LONG,
+    OP_GET_GLOBAL_LONG,
+    OP_SET_GLOBAL_LONG,
+    OP_CLOSURE_LONG,
+    OP_CLASS_LONG,
+    OP_GET_PROPERTY_LONG,
+    OP_SET_PROPERTY_LONG,
+    OP_METHOD_LONG,
+    OP_INVOKE_LONG,
+    OP_GET_SUPER_LONG,
+    OP_SUPER_INVOKE_LONG,
 }
 
 impl From<u8> for OpCode {
@@ -20,6 +63,48 @@ impl From<u8> for OpCode {
             4 => OpCode::OP_DIVIDE,
             5 => OpCode::OP_NEGATE,
             6 => OpCode::OP_RETURN,
+            7 => OpCode::OP_NIL,
+            8 => OpCode::OP_TRUE,
+            9 => OpCode::OP_FALSE,
+            10 => OpCode::OP_NOT,
+            11 => OpCode::OP_EQUAL,
+            12 => OpCode::OP_GREATER,
+            13 => OpCode::OP_LESS,
+            14 => OpCode::OP_PRINT,
+            15 => OpCode::OP_POP,
+            16 => OpCode::OP_DEFINE_GLOBAL,
+            17 => OpCode::OP_GET_GLOBAL,
+            18 => OpCode::OP_SET_GLOBAL,
+            19 => OpCode::OP_GET_LOCAL,
+            20 => OpCode::OP_SET_LOCAL,
+            21 => OpCode::OP_JUMP,
+            22 => OpCode::OP_JUMP_IF_FALSE,
+            23 => OpCode::OP_LOOP,
+            24 => OpCode::OP_CALL,
+            25 => OpCode::OP_CLOSURE,
+            26 => OpCode::OP_GET_UPVALUE,
+            27 => OpCode::OP_SET_UPVALUE,
+            28 => OpCode::OP_CLOSE_UPVALUE,
+            29 => OpCode::OP_CLASS,
+            30 => OpCode::OP_GET_PROPERTY,
+            31 => OpCode::OP_SET_PROPERTY,
+            32 => OpCode::OP_METHOD,
+            33 => OpCode::OP_INVOKE,
+            34 => OpCode::OP_INHERIT,
+            35 => OpCode::OP_GET_SUPER,
+            36 => OpCode::OP_SUPER_INVOKE,
+            37 => OpCode::OP_CONSTANT_LONG,
+            38 => OpCode::OP_DEFINE_GLOBAL_LONG,
+            39 => OpCode::OP_GET_GLOBAL_LONG,
+            40 => OpCode::OP_SET_GLOBAL_LONG,
+            41 => OpCode::OP_CLOSURE_LONG,
+            42 => OpCode::OP_CLASS_LONG,
+            43 => OpCode::OP_GET_PROPERTY_LONG,
+            44 => OpCode::OP_SET_PROPERTY_LONG,
+            45 => OpCode::OP_METHOD_LONG,
+            46 => OpCode::OP_INVOKE_LONG,
+            47 => OpCode::OP_GET_SUPER_LONG,
+            48 => OpCode::OP_SUPER_INVOKE_LONG,
             _ => panic!("Unknown opcode: {}", byte),
         }
     }
@@ -35,6 +120,87 @@ impl From<OpCode> for u8 {
             OpCode::OP_DIVIDE => 4,
             OpCode::OP_NEGATE => 5,
             OpCode::OP_RETURN => 6,
+            OpCode::OP_NIL => 7,
+            OpCode::OP_TRUE => 8,
+            OpCode::OP_FALSE => 9,
+            OpCode::OP_NOT => 10,
+            OpCode::OP_EQUAL => 11,
+            OpCode::OP_GREATER => 12,
+            OpCode::OP_LESS => 13,
+            OpCode::OP_PRINT => 14,
+            OpCode::OP_POP => 15,
+            OpCode::OP_DEFINE_GLOBAL => 16,
+            OpCode::OP_GET_GLOBAL => 17,
+            OpCode::OP_SET_GLOBAL => 18,
+            OpCode::OP_GET_LOCAL => 19,
+            OpCode::OP_SET_LOCAL => 20,
+            OpCode::OP_JUMP => 21,
+            OpCode::OP_JUMP_IF_FALSE => 22,
+            OpCode::OP_LOOP => 23,
+            OpCode::OP_CALL => 24,
+            OpCode::OP_CLOSURE => 25,
+            OpCode::OP_GET_UPVALUE => 26,
+            OpCode::OP_SET_UPVALUE => 27,
+            OpCode::OP_CLOSE_UPVALUE => 28,
+            OpCode::OP_CLASS => 29,
+            OpCode::OP_GET_PROPERTY => 30,
+            OpCode::OP_SET_PROPERTY => 31,
+            OpCode::OP_METHOD => 32,
+            OpCode::OP_INVOKE => 33,
+            OpCode::OP_INHERIT => 34,
+            OpCode::OP_GET_SUPER => 35,
+            OpCode::OP_SUPER_INVOKE => 36,
+            OpCode::OP_CONSTANT_LONG => 37,
+            OpCode::OP_DEFINE_GLOBAL_LONG => 38,
+            OpCode::OP_GET_GLOBAL_LONG => 39,
+            OpCode::OP_SET_GLOBAL_LONG => 40,
+            OpCode::OP_CLOSURE_LONG => 41,
+            OpCode::OP_CLASS_LONG => 42,
+            OpCode::OP_GET_PROPERTY_LONG => 43,
+            OpCode::OP_SET_PROPERTY_LONG => 44,
+            OpCode::OP_METHOD_LONG => 45,
+            OpCode::OP_INVOKE_LONG => 46,
+            OpCode::OP_GET_SUPER_LONG => 47,
+            OpCode::OP_SUPER_INVOKE_LONG => 48,
         }
     }
-}
\ No newline at end of file
+}
+
+impl OpCode {
+    // the variant taking a 24-bit constant index, for opcodes that have one
+    pub fn long_form(self) -> Option<OpCode> {
+        match self {
+            OpCode::OP_CONSTANT => Some(OpCode::OP_CONSTANT_LONG),
+            OpCode::OP_DEFINE_GLOBAL => Some(OpCode::OP_DEFINE_GLOBAL_LONG),
+            OpCode::OP_GET_GLOBAL => Some(OpCode::OP_GET_GLOBAL_LONG),
+            OpCode::OP_SET_GLOBAL => Some(OpCode::OP_SET_GLOBAL_LONG),
+            OpCode::OP_CLOSURE => Some(OpCode::OP_CLOSURE_LONG),
+            OpCode::OP_CLASS => Some(OpCode::OP_CLASS_LONG),
+            OpCode::OP_GET_PROPERTY => Some(OpCode::OP_GET_PROPERTY_LONG),
+            OpCode::OP_SET_PROPERTY => Some(OpCode::OP_SET_PROPERTY_LONG),
+            OpCode::OP_METHOD => Some(OpCode::OP_METHOD_LONG),
+            OpCode::OP_INVOKE => Some(OpCode::OP_INVOKE_LONG),
+            OpCode::OP_GET_SUPER => Some(OpCode::OP_GET_SUPER_LONG),
+            OpCode::OP_SUPER_INVOKE => Some(OpCode::OP_SUPER_INVOKE_LONG),
+            _ => None,
+        }
+    }
+
+    pub fn is_long(self) -> bool {
+        matches!(
+            self,
+            OpCode::OP_CONSTANT_LONG
+                | OpCode::OP_DEFINE_GLOBAL_LONG
+                | OpCode::OP_GET_GLOBAL_LONG
+                | OpCode::OP_SET_GLOBAL_LONG
+                | OpCode::OP_CLOSURE_LONG
+                | OpCode::OP_CLASS_LONG
+                | OpCode::OP_GET_PROPERTY_LONG
+                | OpCode::OP_SET_PROPERTY_LONG
+                | OpCode::OP_METHOD_LONG
+                | OpCode::OP_INVOKE_LONG
+                | OpCode::OP_GET_SUPER_LONG
+                | OpCode::OP_SUPER_INVOKE_LONG
+        )
+    }
+}
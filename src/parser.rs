@@ -1,13 +1,13 @@
-use std::{fmt::format, io::{stderr, Write}};
-
-use crate::{token::{Token, TokenType}, scanner::Scanner};
+use crate::{token::{Span, Token, TokenType}, scanner::Scanner};
+use crate::diagnostic::Diagnostic;
 
 pub struct Parser {
     scanner: Scanner,
     tokens: Vec<Token>,
     current: usize,
     pub had_error: bool,
-    panic_mode: bool,
+    pub panic_mode: bool,
+    pub diagnostics: Vec<Diagnostic>,
 }
 
 impl Parser {
@@ -18,10 +18,11 @@ impl Parser {
             current: 0,
             had_error: false,
             panic_mode: false,
+            diagnostics: Vec::new(),
         }
     }
 
-    fn error_at_current(&mut self, message: &str) {
+    pub fn error_at_current(&mut self, message: &str) {
         let token = &self.tokens[self.current].clone();
         self.error_at(token, message);
     }
@@ -35,22 +36,33 @@ impl Parser {
         &self.tokens[self.current - 1]
     }
 
+    // reports an error at the previous token with extra context lines
+    pub fn error_with_notes(&mut self, message: &str, notes: Vec<String>) {
+        let token = &self.tokens[self.current - 1].clone();
+        self.error_at_with_notes(token, message, notes);
+    }
+
     fn error_at(&mut self, token: &Token, message: &str) {
+        self.error_at_with_notes(token, message, Vec::new());
+    }
+
+    fn error_at_with_notes(&mut self, token: &Token, message: &str, notes: Vec<String>) {
         if self.panic_mode {
             return;
         }
         self.panic_mode = true;
-        let stderr = stderr();
-        let mut stderr_handle = stderr.lock();
-        write!(stderr_handle, "[line {}] Error", token.line).unwrap();
 
+        let mut diagnostic = Diagnostic::error(token.span, message);
         match token.token_type {
-            TokenType::EOF => write!(stderr_handle, " at end").unwrap(),
+            TokenType::EOF => diagnostic = diagnostic.at("at end".to_string()),
             TokenType::ERROR => {}
-            _ => write!(stderr_handle, " at '{}'", token.lexeme).unwrap(),
+            _ => diagnostic = diagnostic.at(format!("at '{}'", token.lexeme)),
+        }
+        for note in notes {
+            diagnostic = diagnostic.with_note(note);
         }
 
-        writeln!(stderr_handle, ": {}", message).unwrap();
+        self.diagnostics.push(diagnostic);
         self.had_error = true;
     }
 
@@ -68,8 +80,43 @@ impl Parser {
         self.error_at_current(message);
     }
 
-    pub fn previous_line(&self) -> usize {
-        self.tokens[self.current - 1].line
+    pub fn check(&self, token_type: TokenType) -> bool {
+        self.current().token_type == token_type
+    }
+
+    pub fn match_token(&mut self, token_type: TokenType) -> bool {
+        if !self.check(token_type) {
+            return false;
+        }
+        self.advance();
+        true
+    }
+
+    // skips tokens until a likely statement boundary so one mistake doesn't
+    // cascade into a stream of follow-on errors
+    pub fn synchronize(&mut self) {
+        self.panic_mode = false;
+
+        while !self.check(TokenType::EOF) {
+            if self.previous().token_type == TokenType::SEMICOLON {
+                return;
+            }
+            match self.current().token_type {
+                TokenType::CLASS
+                | TokenType::FUN
+                | TokenType::VAR
+                | TokenType::FOR
+                | TokenType::IF
+                | TokenType::WHILE
+                | TokenType::PRINT
+                | TokenType::RETURN => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    pub fn previous_span(&self) -> Span {
+        self.tokens[self.current - 1].span
     }
 
     pub fn current(&self) -> &Token {
@@ -83,7 +130,9 @@ impl Parser {
             if token.token_type != TokenType::ERROR {
                 break;
             }
-            self.error_at_current(&format!("Token: {}\nLine: {}", token.lexeme, token.line));
+            // error tokens carry the scanner's message as their lexeme
+            let message = token.lexeme.clone();
+            self.error_at_current(&message);
         }
     }
 }
@@ -1,67 +1,89 @@
-use crate::token::{Token, TokenType};
+use std::rc::Rc;
+
+use crate::token::{Span, Token, TokenType};
 
 pub struct Scanner {
     pub start: usize,
     pub current: usize,
     pub line: usize,
-    pub source: String,
+    // shared with the VM, which renders diagnostics from it afterwards
+    pub source: Rc<String>,
+    // column of the next character, counted in characters
+    column: usize,
+    // position of the token being scanned
+    start_line: usize,
+    start_column: usize,
 }
 
 impl Scanner {
-    pub fn new(source: String) -> Self {
+    pub fn new(source: Rc<String>) -> Self {
         Self {
             start: 0,
             current: 0,
             line: 1,
             source,
+            column: 1,
+            start_line: 1,
+            start_column: 1,
         }
     }
 
+    // where the token starting at offset ends, for spans rebuilt after compiling
+    pub fn token_end(source: Rc<String>, offset: usize) -> usize {
+        let mut scanner = Scanner::new(source);
+        scanner.current = offset;
+        scanner.scan_token().span.end
+    }
+
+    fn span(&self) -> Span {
+        Span::new(self.start, self.current, self.start_line, self.start_column)
+    }
+
     fn make_token(&self, token_type: TokenType) -> Token {
         Token::new(
             token_type,
             self.source[self.start..self.current].to_string(),
-            self.line,
+            self.span(),
         )
     }
 
     fn error_token(&self, message: &str) -> Token {
-        Token::new(TokenType::ERROR, message.to_string(), self.line)
+        Token::new(TokenType::ERROR, message.to_string(), self.span())
+    }
+
+    fn newline(&mut self) {
+        self.advance();
+        self.line += 1;
+        self.column = 1;
     }
 
     fn is_at_end(&self) -> bool {
         self.current >= self.source.len()
     }
 
+    // current is a byte offset, so step over the whole UTF-8 sequence
     fn advance(&mut self) -> char {
-        let c = self.source.chars().nth(self.current).unwrap();
-        self.current += 1;
+        let c = self.peek();
+        self.current += c.len_utf8();
+        self.column += 1;
         c
     }
 
     fn expect(&mut self, expected: char) -> bool {
-        if self.is_at_end() {
-            return false;
-        }
-        if self.source.chars().nth(self.current).unwrap() != expected {
+        if self.is_at_end() || self.peek() != expected {
             return false;
         }
-        self.current += 1;
+        self.current += expected.len_utf8();
+        self.column += 1;
         true
     }
 
     fn peek(&self) -> char {
-        if self.is_at_end() {
-            return '\0';
-        }
-        self.source.chars().nth(self.current).unwrap()
+        self.source[self.current..].chars().next().unwrap_or('\0')
     }
 
     fn peek_next(&self) -> char {
-        if self.current + 1 >= self.source.len() {
-            return '\0';
-        }
-        self.source.chars().nth(self.current + 1).unwrap()
+        self.source[self.current..].chars().nth(1).unwrap_or('\0')
     }
 
     fn skip_whitespace(&mut self) {
@@ -71,10 +93,7 @@ impl Scanner {
                 ' ' | '\r' | '\t' => {
                     self.advance();
                 }
-                '\n' => {
-                    self.line += 1;
-                    self.advance();
-                }
+                '\n' => self.newline(),
                 _ => {
                     break;
                 }
@@ -85,9 +104,10 @@ impl Scanner {
     fn string(&mut self) -> Token {
         while self.peek() != '"' && !self.is_at_end() {
             if self.peek() == '\n' {
-                self.line += 1;
+                self.newline();
+            } else {
+                self.advance();
             }
-            self.advance();
         }
 
         if self.is_at_end() {
@@ -99,22 +119,26 @@ impl Scanner {
     }
 
     fn check_keyword(
-        &mut self,
+        &self,
         start: usize,
         length: usize,
         rest: &str,
         token_type: TokenType,
     ) -> TokenType {
         if self.current - self.start == start + length
-            && &self.source[self.start..self.current] == rest
+            && &self.source.as_bytes()[self.start + start..self.current] == rest.as_bytes()
         {
             return token_type;
         }
         TokenType::IDENTIFIER
     }
 
-    fn identifier_type(&mut self) -> TokenType {
-        match self.peek() {
+    fn char_at(&self, index: usize) -> char {
+        self.source.as_bytes()[index] as char
+    }
+
+    fn identifier_type(&self) -> TokenType {
+        match self.char_at(self.start) {
             'a' => self.check_keyword(1, 2, "nd", TokenType::AND),
             'c' => self.check_keyword(1, 4, "lass", TokenType::CLASS),
             'e' => self.check_keyword(1, 3, "lse", TokenType::ELSE),
@@ -128,7 +152,7 @@ impl Scanner {
             'w' => self.check_keyword(1, 4, "hile", TokenType::WHILE),
             'f' => {
                 if self.current - self.start > 1 {
-                    match self.peek() {
+                    match self.char_at(self.start + 1) {
                         'a' => self.check_keyword(2, 3, "lse", TokenType::FALSE),
                         'o' => self.check_keyword(2, 1, "r", TokenType::FOR),
                         'u' => self.check_keyword(2, 1, "n", TokenType::FUN),
@@ -140,7 +164,7 @@ impl Scanner {
             }
             't' => {
                 if self.current - self.start > 1 {
-                    match self.peek() {
+                    match self.char_at(self.start + 1) {
                         'h' => self.check_keyword(2, 2, "is", TokenType::THIS),
                         'r' => self.check_keyword(2, 2, "ue", TokenType::TRUE),
                         _ => TokenType::IDENTIFIER,
@@ -149,7 +173,6 @@ impl Scanner {
                     TokenType::IDENTIFIER
                 }
             }
-            'v' => self.check_keyword(1, 2, "ar", TokenType::VAR),
             _ => TokenType::IDENTIFIER,
         }
     }
@@ -165,6 +188,8 @@ impl Scanner {
     pub fn scan_token(&mut self) -> Token {
         self.skip_whitespace();
         self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.column;
 
         if self.is_at_end() {
             return self.make_token(TokenType::EOF);
@@ -184,7 +209,7 @@ impl Scanner {
             '+' => self.make_token(TokenType::PLUS),
             '/' => {
                 if self.expect('/') {
-                    while self.peek_next() != '\n' && !self.is_at_end() {
+                    while self.peek() != '\n' && !self.is_at_end() {
                         self.advance();
                     }
                     self.scan_token()
@@ -224,20 +249,54 @@ impl Scanner {
             '"' => self.string(),
             'a'..='z' | 'A'..='Z' => self.identifier(),
             '0'..='9' => {
-                while self.peek().is_digit(10) {
+                while self.peek().is_ascii_digit() {
                     self.advance();
                 }
 
-                if self.peek() == '.' && self.peek_next().is_digit(10) {
+                if self.peek() == '.' && self.peek_next().is_ascii_digit() {
                     self.advance();
-                    while self.peek().is_digit(10) {
+                    while self.peek().is_ascii_digit() {
                         self.advance();
                     }
                 }
 
-                return self.make_token(TokenType::NUMBER);
+                self.make_token(TokenType::NUMBER)
             }
             _ => self.error_token(&format!("Unexpected character: {}", c)),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_record_line_and_column() {
+        let mut scanner = Scanner::new(Rc::new("var a;\n  print \"x\ny\" + a;".to_string()));
+        let spans: Vec<Span> = std::iter::from_fn(|| {
+            let token = scanner.scan_token();
+            (token.token_type != TokenType::EOF).then_some(token.span)
+        })
+        .collect();
+        let positions: Vec<(usize, usize)> = spans.iter().map(|span| (span.line, span.column)).collect();
+        assert_eq!(positions, vec![(1, 1), (1, 5), (1, 6), (2, 3), (2, 9), (3, 4), (3, 6), (3, 7)]);
+        assert_eq!((spans[4].start, spans[4].end), (15, 20));
+    }
+
+    #[test]
+    fn non_ascii_source_is_scanned_by_character() {
+        let mut scanner = Scanner::new(Rc::new("// é\nprint \"é\" é;".to_string()));
+        let tokens: Vec<Token> = std::iter::from_fn(|| {
+            let token = scanner.scan_token();
+            (token.token_type != TokenType::EOF).then_some(token)
+        })
+        .collect();
+        assert_eq!(tokens[1].lexeme, "\"é\"");
+        assert_eq!((tokens[1].span.start, tokens[1].span.end), (12, 16));
+        assert_eq!(tokens[2].token_type, TokenType::ERROR);
+        assert_eq!(tokens[2].lexeme, "Unexpected character: é");
+        let positions: Vec<(usize, usize)> = tokens.iter().map(|token| (token.span.line, token.span.column)).collect();
+        assert_eq!(positions, vec![(2, 1), (2, 7), (2, 11), (2, 12)]);
+    }
+}
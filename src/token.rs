@@ -1,21 +1,43 @@
+// where a token sits in the source, line and column are 1-based
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Span {
+    // byte offsets of the token's first byte and one past its last
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize, line: usize, column: usize) -> Self {
+        Self {
+            start,
+            end,
+            line,
+            column,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Token {
     pub token_type: TokenType,
     pub lexeme: String,
-    pub line: usize,
+    pub span: Span,
 }
 
 
 impl Token {
-    pub fn new(token_type: TokenType, lexeme: String, line: usize) -> Self {
+    pub fn new(token_type: TokenType, lexeme: String, span: Span) -> Self {
         Self {
             token_type,
             lexeme,
-            line,
+            span,
         }
     }
 }
 
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, PartialEq, Copy)]
 pub enum TokenType {
     LEFT_PAREN,
@@ -1,54 +1,118 @@
-#[derive(Debug, Clone, Copy)]
+use std::rc::Rc;
+
+use crate::gc::Gc;
+use crate::object::{ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjNative, ObjString};
+
+#[derive(Clone, Copy, Default)]
 pub enum Value {
+    Bool(bool),
+    #[default]
+    Nil,
     Number(f64),
+    String(Gc<ObjString>),
+    Function(Gc<ObjFunction>),
+    Closure(Gc<ObjClosure>),
+    Class(Gc<ObjClass>),
+    Instance(Gc<ObjInstance>),
+    BoundMethod(Gc<ObjBoundMethod>),
+    Native(Gc<ObjNative>),
 }
 
-impl std::ops::Neg for Value {
-    type Output = Self;
+impl Value {
+    // nil and false are falsey, every other value is truthy
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+}
 
-    fn neg(self) -> Self {
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            Value::Number(n) => Value::Number(-n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Nil => write!(f, "nil"),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::String(s) => write!(f, "{}", s),
+            Value::Function(function) => write!(f, "{}", function),
+            Value::Closure(closure) => write!(f, "{}", closure.function),
+            Value::Class(class) => write!(f, "{}", class.name),
+            Value::Instance(instance) => write!(f, "{} instance", instance.class.name),
+            Value::BoundMethod(bound) => write!(f, "{}", bound.method.function),
+            Value::Native(_) => write!(f, "<native fn>"),
         }
     }
 }
 
-impl std::ops::Add for Value {
-    type Output = Self;
-
-    fn add(self, rhs: Self) -> Self {
-        match (self, rhs) {
-            (Value::Number(a), Value::Number(b)) => Value::Number(a + b),
+// objects are shown by their printed form, following references could recurse forever
+impl std::fmt::Debug for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Value::Bool(b) => write!(f, "Bool({})", b),
+            Value::Nil => write!(f, "Nil"),
+            Value::Number(n) => write!(f, "Number({:?})", n),
+            Value::String(s) => write!(f, "String({:?})", s.chars),
+            Value::Function(_) => write!(f, "Function({})", self),
+            Value::Closure(_) => write!(f, "Closure({})", self),
+            Value::Class(_) => write!(f, "Class({})", self),
+            Value::Instance(_) => write!(f, "Instance({})", self),
+            Value::BoundMethod(_) => write!(f, "BoundMethod({})", self),
+            Value::Native(native) => write!(f, "Native({})", native.name),
         }
     }
 }
 
-impl std::ops::Sub for Value {
-    type Output = Self;
-
-    fn sub(self, rhs: Self) -> Self {
-        match (self, rhs) {
-            (Value::Number(a), Value::Number(b)) => Value::Number(a - b),
+// values of different types are never equal
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Nil, Value::Nil) => true,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            // strings are interned, so identity is equality
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Function(a), Value::Function(b)) => a == b,
+            (Value::Closure(a), Value::Closure(b)) => a == b,
+            (Value::Class(a), Value::Class(b)) => a == b,
+            (Value::Instance(a), Value::Instance(b)) => a == b,
+            (Value::BoundMethod(a), Value::BoundMethod(b)) => a == b,
+            (Value::Native(a), Value::Native(b)) => a == b,
+            _ => false,
         }
     }
 }
 
-impl std::ops::Mul for Value {
-    type Output = Self;
+// A value as the host sees it. Heap handles are only valid while the collector
+// can reach them, so they never leave the VM: strings are shared by their
+// contents and every other object is described by its printed form.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum LoxValue {
+    Bool(bool),
+    #[default]
+    Nil,
+    Number(f64),
+    String(Rc<str>),
+    Object(String),
+}
 
-    fn mul(self, rhs: Self) -> Self {
-        match (self, rhs) {
-            (Value::Number(a), Value::Number(b)) => Value::Number(a * b),
+impl From<Value> for LoxValue {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Bool(b) => LoxValue::Bool(b),
+            Value::Nil => LoxValue::Nil,
+            Value::Number(n) => LoxValue::Number(n),
+            Value::String(s) => LoxValue::String(s.chars.clone()),
+            object => LoxValue::Object(object.to_string()),
         }
     }
 }
 
-impl std::ops::Div for Value {
-    type Output = Self;
-
-    fn div(self, rhs: Self) -> Self {
-        match (self, rhs) {
-            (Value::Number(a), Value::Number(b)) => Value::Number(a / b),
+impl std::fmt::Display for LoxValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LoxValue::Bool(b) => write!(f, "{}", b),
+            LoxValue::Nil => write!(f, "nil"),
+            LoxValue::Number(n) => write!(f, "{}", n),
+            LoxValue::String(s) => write!(f, "{}", s),
+            LoxValue::Object(description) => write!(f, "{}", description),
         }
     }
 }
@@ -66,8 +130,4 @@ impl ValueArray {
     pub fn write_value(&mut self, value: Value) {
         self.data.push(value);
     }
-
-    pub fn free(&mut self) {
-        self.data.clear();
-    }
 }
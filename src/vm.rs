@@ -1,140 +1,1253 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use crate::opcode::OpCode;
-use crate::chunk::Chunk;
-use crate::value::Value;
+use crate::value::{LoxValue, Value};
+use crate::object::{ObjBoundMethod, ObjClass, ObjClosure, ObjInstance, ObjNative, ObjString, ObjUpvalue, NativeFn, UpvalueState};
 use crate::compiler::Compiler;
+use crate::gc::{Gc, Heap};
+use crate::diagnostic::DiagnosticStyle;
+use crate::token::Span;
+use crate::error::LoxError;
 
-pub enum InterpretResult {
-    Ok,
-    CompileError,
-    RuntimeError,
+// a call that was active when a runtime error happened
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    // None for the top-level script
+    pub function: Option<String>,
+    pub span: Span,
 }
 
-const STACK_MAX: usize = 256;
+impl std::fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "[line {}] in {}()", self.span.line, name),
+            None => write!(f, "[line {}] in script", self.span.line),
+        }
+    }
+}
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    // line of the instruction that failed
+    pub line: usize,
+    // innermost call first
+    pub backtrace: Vec<TraceFrame>,
+}
 
-pub struct VM {
-    compiler: Compiler,
-    chunk: Chunk,
+impl std::error::Error for RuntimeError {}
+
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "{}", self.message)?;
+        for frame in &self.backtrace {
+            writeln!(f, "{}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+const FRAMES_MAX: usize = 64;
+const STACK_MAX: usize = FRAMES_MAX * (u8::MAX as usize + 1);
+// heap growth charged for each new entry in a class's method table
+const METHOD_ENTRY_SIZE: usize = std::mem::size_of::<(Gc<ObjString>, Gc<ObjClosure>)>();
+
+#[derive(Debug, Clone, Copy)]
+pub struct VMConfig {
+    // number of value slots on the stack, the whole stack is allocated when
+    // the VM is built so this is a memory cost up front, not just a limit
+    pub stack_max: usize,
+    // deepest allowed call nesting, frames are allocated as calls are made so
+    // usize::MAX leaves it unlimited
+    pub frames_max: usize,
+}
+
+impl Default for VMConfig {
+    fn default() -> Self {
+        Self {
+            stack_max: STACK_MAX,
+            frames_max: FRAMES_MAX,
+        }
+    }
+}
+
+struct CallFrame {
+    closure: Gc<ObjClosure>,
     ip: usize,
-    stack: [Value; STACK_MAX],
+    // index of the frame's first stack slot
+    slots: usize,
+}
+
+pub struct VM {
+    frames: Vec<CallFrame>,
+    stack: Box<[Value]>,
     stack_top: usize,
+    pub(crate) heap: Heap,
+    globals: HashMap<Gc<ObjString>, Value>,
+    // upvalues still pointing into the stack, sorted by slot
+    open_upvalues: Vec<Gc<ObjUpvalue>>,
+    init_string: Gc<ObjString>,
+    // how compile errors are printed
+    diagnostic_style: DiagnosticStyle,
+    config: VMConfig,
+    // where print statements write
+    stdout: Box<dyn Write>,
+    // compile and runtime errors
+    stderr: Box<dyn Write>,
+    // disassembly and stack dumps from the debug features
+    pub(crate) trace: Box<dyn Write>,
+}
+
+pub struct VMBuilder {
+    config: VMConfig,
+    diagnostic_style: DiagnosticStyle,
+    stdout: Box<dyn Write>,
+    stderr: Box<dyn Write>,
+    trace: Box<dyn Write>,
+}
+
+impl VMBuilder {
+    fn new() -> Self {
+        Self {
+            config: VMConfig::default(),
+            diagnostic_style: DiagnosticStyle::default(),
+            stdout: Box::new(std::io::stdout()),
+            stderr: Box::new(std::io::stderr()),
+            trace: Box::new(std::io::stdout()),
+        }
+    }
+
+    pub fn config(mut self, config: VMConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn diagnostic_style(mut self, style: DiagnosticStyle) -> Self {
+        self.diagnostic_style = style;
+        self
+    }
+
+    pub fn stdout(mut self, writer: impl Write + 'static) -> Self {
+        self.stdout = Box::new(writer);
+        self
+    }
+
+    pub fn stderr(mut self, writer: impl Write + 'static) -> Self {
+        self.stderr = Box::new(writer);
+        self
+    }
+
+    pub fn trace(mut self, writer: impl Write + 'static) -> Self {
+        self.trace = Box::new(writer);
+        self
+    }
+
+    pub fn build(self) -> VM {
+        let config = self.config;
+        let mut heap = Heap::new();
+        let init_string = heap.intern("init");
+        let mut vm = VM {
+            frames: Vec::new(),
+            stack: vec![Value::Nil; config.stack_max].into_boxed_slice(),
+            stack_top: 0,
+            heap,
+            globals: HashMap::new(),
+            open_upvalues: Vec::new(),
+            init_string,
+            diagnostic_style: self.diagnostic_style,
+            config,
+            stdout: self.stdout,
+            stderr: self.stderr,
+            trace: self.trace,
+        };
+        vm.define_native("clock", 0, clock_native);
+        vm
+    }
+}
+
+fn clock_native(_args: &[LoxValue]) -> Result<LoxValue, String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| "System clock is before the epoch.".to_string())?;
+    Ok(LoxValue::Number(now.as_secs_f64()))
 }
 
 // BINARY_OP macro
 macro_rules! binary_op {
-    ($self:ident, $op:tt) => {
+    ($self:ident, $value_type:path, $op:tt) => {
         {
             let b = $self.pop();
             let a = $self.pop();
-            $self.push(a $op b);
+            match (a, b) {
+                (Value::Number(a), Value::Number(b)) => $self.push($value_type(a $op b))?,
+                _ => return Err($self.runtime_error("Operands must be numbers.")),
+            }
         }
     };
 }
 
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl VM {
     pub fn new() -> Self {
-        Self {
-            compiler: Compiler::new(String::new()),
-            chunk: Chunk::new(),
-            ip: 0,
-            stack: [Value::Number(0.0); STACK_MAX],
-            stack_top: 0,
-        }
+        Self::builder().build()
+    }
+
+    pub fn builder() -> VMBuilder {
+        VMBuilder::new()
+    }
+
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        let name = self.heap.intern(name);
+        let native = self.heap.alloc(ObjNative::new(name, arity, function));
+        self.globals.insert(name, Value::Native(native));
     }
 
-    pub fn stack_trace(&self) {
-        for i in (0..self.stack_top).rev() {
-            println!("[{:?}]", self.stack[i]);
+    pub fn stack_trace(&mut self) {
+        // trace output is best effort, a broken writer shouldn't stop the script
+        for value in self.stack[..self.stack_top].iter().rev() {
+            let _ = writeln!(self.trace, "[{}]", value);
         }
     }
 
-    fn push(&mut self, value: Value) {
+    fn reset_stack(&mut self) {
+        self.stack_top = 0;
+        self.frames.clear();
+        self.open_upvalues.clear();
+    }
+
+    fn backtrace(&self) -> Vec<TraceFrame> {
+        self.frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = &frame.closure.function;
+                TraceFrame {
+                    function: function.name.map(|name| name.to_string()),
+                    // ip has already moved past the failing instruction
+                    span: function.chunk.get_span(frame.ip - 1),
+                }
+            })
+            .collect()
+    }
+
+    fn runtime_error(&mut self, message: &str) -> RuntimeError {
+        let backtrace = self.backtrace();
+        let error = RuntimeError {
+            message: message.to_string(),
+            line: backtrace.first().map_or(0, |frame| frame.span.line),
+            backtrace,
+        };
+        // the caller gets the error either way, so a failed write loses nothing
+        let _ = write!(self.stderr, "{}", error);
+        self.reset_stack();
+        error
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn push(&mut self, value: Value) -> Result<(), RuntimeError> {
+        if self.stack_top == self.stack.len() {
+            return Err(self.runtime_error("Stack overflow."));
+        }
         self.stack[self.stack_top] = value;
         self.stack_top += 1;
+        Ok(())
+    }
+
+    fn peek(&self, distance: usize) -> Value {
+        self.stack[self.stack_top - 1 - distance]
     }
 
     fn pop(&mut self) -> Value {
         self.stack_top -= 1;
         self.stack[self.stack_top]
     }
+
+    fn mark_roots(&mut self) {
+        for i in 0..self.stack_top {
+            self.heap.mark_value(self.stack[i]);
+        }
+        for frame in &self.frames {
+            self.heap.mark_object(frame.closure);
+        }
+        for &upvalue in &self.open_upvalues {
+            self.heap.mark_object(upvalue);
+        }
+        for (&name, &value) in &self.globals {
+            self.heap.mark_object(name);
+            self.heap.mark_value(value);
+        }
+        self.heap.mark_object(self.init_string);
+    }
+
+    pub fn collect_garbage(&mut self) {
+        self.mark_roots();
+        self.heap.collect(&mut self.trace);
+    }
     
-    pub fn interpret(&mut self, source: String) -> InterpretResult {
-        self.compiler = Compiler::new(source);
-        self.compiler.compile();
-        self.chunk = self.compiler.current_chunk.clone();
-        self.ip = 0;
-        self.run()
+    // Runs a script. Top-level code can't return a value, so a successful run
+    // always gives Ok(LoxValue::Nil), errors are also written to the
+    // diagnostics writer.
+    pub fn interpret(&mut self, source: String) -> Result<LoxValue, LoxError> {
+        let source = Rc::new(source);
+        let function = match Compiler::new(Rc::clone(&source), self).compile() {
+            Ok(function) => self.heap.alloc(function),
+            Err(diagnostics) => {
+                // also returned below, so ignore a writer that fails
+                for diagnostic in &diagnostics {
+                    let rendered = diagnostic.render(&source, self.diagnostic_style);
+                    let _ = write!(self.stderr, "{}", rendered);
+                }
+                return Err(LoxError::Compile(diagnostics));
+            }
+        };
+        let closure = self.heap.alloc(ObjClosure::new(function, Vec::new()));
+        self.push(Value::Closure(closure))
+            .and_then(|_| self.call(closure, 0))
+            .and_then(|_| self.run())
+            .map(LoxValue::from)
+            .map_err(LoxError::Runtime)
+    }
+
+    fn call(&mut self, closure: Gc<ObjClosure>, arg_count: usize) -> Result<(), RuntimeError> {
+        let arity = closure.function.arity;
+        if arg_count != arity {
+            return Err(self.runtime_error(&format!("Expected {} arguments but got {}.", arity, arg_count)));
+        }
+
+        if self.frames.len() == self.config.frames_max {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slots: self.stack_top - arg_count - 1,
+        });
+        Ok(())
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), RuntimeError> {
+        match callee {
+            Value::BoundMethod(bound) => {
+                self.stack[self.stack_top - arg_count - 1] = bound.receiver;
+                self.call(bound.method, arg_count)
+            }
+            Value::Class(class) => {
+                let instance = self.heap.alloc(ObjInstance::new(class));
+                self.stack[self.stack_top - arg_count - 1] = Value::Instance(instance);
+                let initializer = class.methods.borrow().get(&self.init_string).cloned();
+                match initializer {
+                    Some(initializer) => self.call(initializer, arg_count),
+                    None if arg_count != 0 => Err(self.runtime_error(&format!("Expected 0 arguments but got {}.", arg_count))),
+                    None => Ok(()),
+                }
+            }
+            Value::Closure(closure) => self.call(closure, arg_count),
+            Value::Native(native) => {
+                if arg_count != native.arity {
+                    return Err(self.runtime_error(&format!("Expected {} arguments but got {}.", native.arity, arg_count)));
+                }
+                let args: Vec<LoxValue> = self.stack[self.stack_top - arg_count..self.stack_top]
+                    .iter()
+                    .map(|arg| LoxValue::from(*arg))
+                    .collect();
+                let result = match (native.function)(&args) {
+                    Ok(LoxValue::Bool(b)) => Value::Bool(b),
+                    Ok(LoxValue::Nil) => Value::Nil,
+                    Ok(LoxValue::Number(n)) => Value::Number(n),
+                    Ok(LoxValue::String(s)) => Value::String(self.heap.intern(&s)),
+                    Ok(LoxValue::Object(_)) => {
+                        return Err(self.runtime_error("Native functions can't return objects."))
+                    }
+                    Err(message) => return Err(self.runtime_error(&message)),
+                };
+                self.stack_top -= arg_count + 1;
+                self.push(result)
+            }
+            _ => {
+                Err(self.runtime_error("Can only call functions and classes."))
+            }
+        }
+    }
+
+    fn invoke_from_class(&mut self, class: &ObjClass, name: &Gc<ObjString>, arg_count: usize) -> Result<(), RuntimeError> {
+        let method = class.methods.borrow().get(name).cloned();
+        match method {
+            Some(method) => self.call(method, arg_count),
+            None => {
+                Err(self.runtime_error(&format!("Undefined property '{}'.", name)))
+            }
+        }
+    }
+
+    // calls a method directly, without creating an intermediate bound method
+    fn invoke(&mut self, name: &Gc<ObjString>, arg_count: usize) -> Result<(), RuntimeError> {
+        let instance = match self.peek(arg_count) {
+            Value::Instance(instance) => instance,
+            _ => {
+                return Err(self.runtime_error("Only instances have methods."));
+            }
+        };
+
+        // a field holding a function shadows a method of the same name
+        let field = instance.fields.borrow().get(name).cloned();
+        if let Some(value) = field {
+            self.stack[self.stack_top - arg_count - 1] = value;
+            return self.call_value(value, arg_count);
+        }
+
+        self.invoke_from_class(&instance.class, name, arg_count)
+    }
+
+    // replaces the instance on top of the stack with its method bound to it
+    fn bind_method(&mut self, class: &ObjClass, name: &Gc<ObjString>) -> Result<(), RuntimeError> {
+        let method = class.methods.borrow().get(name).cloned();
+        match method {
+            Some(method) => {
+                let bound = ObjBoundMethod::new(self.peek(0), method);
+                self.pop();
+                let bound = self.heap.alloc(bound);
+                self.push(Value::BoundMethod(bound))?;
+                Ok(())
+            }
+            None => {
+                Err(self.runtime_error(&format!("Undefined property '{}'.", name)))
+            }
+        }
+    }
+
+    fn define_method(&mut self, name: Gc<ObjString>) {
+        let method = match self.peek(0) {
+            Value::Closure(closure) => closure,
+            value => panic!("Expected method closure, found {:?}", value),
+        };
+        if let Value::Class(class) = self.peek(1) {
+            if class.methods.borrow_mut().insert(name, method).is_none() {
+                self.heap.grow(class, METHOD_ENTRY_SIZE);
+            }
+        }
+        self.pop();
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> Gc<ObjUpvalue> {
+        let mut index = self.open_upvalues.len();
+        while index > 0 {
+            let upvalue = &self.open_upvalues[index - 1];
+            match *upvalue.state.borrow() {
+                UpvalueState::Open(open_slot) if open_slot == slot => return *upvalue,
+                UpvalueState::Open(open_slot) if open_slot < slot => break,
+                _ => index -= 1,
+            }
+        }
+
+        let upvalue = self.heap.alloc(ObjUpvalue::new(slot));
+        self.open_upvalues.insert(index, upvalue);
+        upvalue
     }
 
-    // run with stack trace
-    // TODO: implement compiler flag to enable/disable stack trace
-    pub fn debug_interpret(&mut self, source: String) -> InterpretResult {
-        self.compiler = Compiler::new(source);
-        self.compiler.compile();
-        self.chunk = self.compiler.current_chunk.clone();
-        self.ip = 0;
-        self.debug_run()
+    // moves every variable at or above the given slot off the stack into its upvalue
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let slot = match *upvalue.state.borrow() {
+                UpvalueState::Open(slot) => slot,
+                UpvalueState::Closed(_) => unreachable!("closed upvalue in open list"),
+            };
+            if slot < last {
+                break;
+            }
+            upvalue.state.replace(UpvalueState::Closed(self.stack[slot]));
+            self.open_upvalues.pop();
+        }
     }
 
     fn read_byte(&mut self) -> u8 {
-        let byte = self.chunk.code[self.ip];
-        self.ip += 1;
+        let frame = self.frame_mut();
+        let byte = frame.closure.function.chunk.code[frame.ip];
+        frame.ip += 1;
         byte
     }
 
-    fn read_constant(&mut self) -> Value {
-        let constant = self.read_byte();
-        self.chunk.values.data[constant as usize]
+    fn read_short(&mut self) -> u16 {
+        let high = self.read_byte();
+        let low = self.read_byte();
+        u16::from_be_bytes([high, low])
+    }
+
+    // long forms of an instruction carry a 24-bit constant index
+    fn read_constant(&mut self, long: bool) -> Value {
+        let constant = if long {
+            u32::from_be_bytes([0, self.read_byte(), self.read_byte(), self.read_byte()]) as usize
+        } else {
+            self.read_byte() as usize
+        };
+        self.frame().closure.function.chunk.values.data[constant]
+    }
+
+    fn read_string(&mut self, long: bool) -> Gc<ObjString> {
+        match self.read_constant(long) {
+            Value::String(string) => string,
+            value => panic!("Expected string constant, found {:?}", value),
+        }
     }
 
-    fn debug_run(&mut self) -> InterpretResult {
+    fn run(&mut self) -> Result<Value, RuntimeError> {
         loop {
-            self.stack_trace();
+            // every value is reachable from a root between instructions
+            if self.heap.should_collect() {
+                self.collect_garbage();
+            }
+
+            if cfg!(feature = "debug_trace_execution") {
+                self.stack_trace();
+                let frame = self.frames.last().unwrap();
+                let _ = frame.closure.function.chunk.disassemble_instruction(&mut self.trace, frame.ip);
+            }
             let instruction: OpCode = self.read_byte().into();
+            let long = instruction.is_long();
 
             match instruction {
-                OpCode::OP_CONSTANT => {
-                    let constant = self.read_constant();
-                    self.push(constant);
-                }
-                OpCode::OP_ADD => binary_op!(self, +),
-                OpCode::OP_SUBTRACT => binary_op!(self, -),
-                OpCode::OP_MULTIPLY => binary_op!(self, *),
-                OpCode::OP_DIVIDE => binary_op!(self, /),
-                OpCode::OP_NEGATE => {
+                OpCode::OP_CONSTANT | OpCode::OP_CONSTANT_LONG => {
+                    let constant = self.read_constant(long);
+                    self.push(constant)?;
+                }
+                OpCode::OP_NIL => self.push(Value::Nil)?,
+                OpCode::OP_TRUE => self.push(Value::Bool(true))?,
+                OpCode::OP_FALSE => self.push(Value::Bool(false))?,
+                OpCode::OP_POP => {
+                    self.pop();
+                }
+                OpCode::OP_GET_LOCAL => {
+                    let slot = self.frame().slots + self.read_byte() as usize;
+                    self.push(self.stack[slot])?;
+                }
+                OpCode::OP_SET_LOCAL => {
+                    let slot = self.frame().slots + self.read_byte() as usize;
+                    self.stack[slot] = self.peek(0);
+                }
+                OpCode::OP_GET_UPVALUE => {
+                    let slot = self.read_byte() as usize;
+                    let upvalue = self.frame().closure.upvalues[slot];
+                    let value = match &*upvalue.state.borrow() {
+                        UpvalueState::Open(slot) => self.stack[*slot],
+                        UpvalueState::Closed(value) => *value,
+                    };
+                    self.push(value)?;
+                }
+                OpCode::OP_SET_UPVALUE => {
+                    let slot = self.read_byte() as usize;
+                    let upvalue = self.frame().closure.upvalues[slot];
+                    let value = self.peek(0);
+                    match &mut *upvalue.state.borrow_mut() {
+                        UpvalueState::Open(slot) => self.stack[*slot] = value,
+                        UpvalueState::Closed(closed) => *closed = value,
+                    };
+                }
+                OpCode::OP_GET_GLOBAL | OpCode::OP_GET_GLOBAL_LONG => {
+                    let name = self.read_string(long);
+                    match self.globals.get(&name) {
+                        Some(value) => {
+                            let value = *value;
+                            self.push(value)?;
+                        }
+                        None => return Err(self.runtime_error(&format!("Undefined variable '{}'.", name))),
+                    }
+                }
+                OpCode::OP_DEFINE_GLOBAL | OpCode::OP_DEFINE_GLOBAL_LONG => {
+                    let name = self.read_string(long);
+                    let value = self.peek(0);
+                    self.globals.insert(name, value);
+                    self.pop();
+                }
+                OpCode::OP_SET_GLOBAL | OpCode::OP_SET_GLOBAL_LONG => {
+                    let name = self.read_string(long);
+                    if !self.globals.contains_key(&name) {
+                        return Err(self.runtime_error(&format!("Undefined variable '{}'.", name)));
+                    }
+                    let value = self.peek(0);
+                    self.globals.insert(name, value);
+                }
+                OpCode::OP_GET_PROPERTY | OpCode::OP_GET_PROPERTY_LONG => {
+                    let instance = match self.peek(0) {
+                        Value::Instance(instance) => instance,
+                        _ => return Err(self.runtime_error("Only instances have properties.")),
+                    };
+                    let name = self.read_string(long);
+
+                    let field = instance.fields.borrow().get(&name).cloned();
+                    if let Some(value) = field {
+                        self.pop();
+                        self.push(value)?;
+                    } else {
+                        self.bind_method(&instance.class, &name)?;
+                    }
+                }
+                OpCode::OP_SET_PROPERTY | OpCode::OP_SET_PROPERTY_LONG => {
+                    let instance = match self.peek(1) {
+                        Value::Instance(instance) => instance,
+                        _ => return Err(self.runtime_error("Only instances have fields.")),
+                    };
+                    let name = self.read_string(long);
+                    let value = self.pop();
+                    if instance.fields.borrow_mut().insert(name, value).is_none() {
+                        self.heap.grow(instance, std::mem::size_of::<(Gc<ObjString>, Value)>());
+                    }
+                    self.pop();
+                    self.push(value)?;
+                }
+                OpCode::OP_GET_SUPER | OpCode::OP_GET_SUPER_LONG => {
+                    let name = self.read_string(long);
+                    let superclass = match self.pop() {
+                        Value::Class(class) => class,
+                        value => panic!("Expected superclass, found {:?}", value),
+                    };
+                    self.bind_method(&superclass, &name)?;
+                }
+                OpCode::OP_EQUAL => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    self.push(Value::Bool(a == b))?;
+                }
+                OpCode::OP_GREATER => binary_op!(self, Value::Bool, >),
+                OpCode::OP_LESS => binary_op!(self, Value::Bool, <),
+                OpCode::OP_ADD => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    match (a, b) {
+                        (Value::String(a), Value::String(b)) => {
+                            let result = self.heap.intern(&format!("{}{}", a, b));
+                            self.push(Value::String(result))?
+                        }
+                        (Value::Number(a), Value::Number(b)) => self.push(Value::Number(a + b))?,
+                        _ => return Err(self.runtime_error("Operands must be two numbers or two strings.")),
+                    }
+                }
+                OpCode::OP_SUBTRACT => binary_op!(self, Value::Number, -),
+                OpCode::OP_MULTIPLY => binary_op!(self, Value::Number, *),
+                OpCode::OP_DIVIDE => binary_op!(self, Value::Number, /),
+                OpCode::OP_NOT => {
+                    let value = self.pop();
+                    self.push(Value::Bool(value.is_falsey()))?;
+                }
+                OpCode::OP_NEGATE => match self.pop() {
+                    Value::Number(n) => self.push(Value::Number(-n))?,
+                    _ => return Err(self.runtime_error("Operand must be a number.")),
+                },
+                OpCode::OP_PRINT => {
                     let value = self.pop();
-                    self.push(-value);
+                    if let Err(error) = writeln!(self.stdout, "{}", value) {
+                        return Err(self.runtime_error(&format!("Error writing output: {}.", error)));
+                    }
+                }
+                OpCode::OP_JUMP => {
+                    let offset = self.read_short();
+                    self.frame_mut().ip += offset as usize;
+                }
+                OpCode::OP_JUMP_IF_FALSE => {
+                    let offset = self.read_short();
+                    if self.peek(0).is_falsey() {
+                        self.frame_mut().ip += offset as usize;
+                    }
+                }
+                OpCode::OP_LOOP => {
+                    let offset = self.read_short();
+                    self.frame_mut().ip -= offset as usize;
+                }
+                OpCode::OP_CALL => {
+                    let arg_count = self.read_byte() as usize;
+                    let callee = self.peek(arg_count);
+                    self.call_value(callee, arg_count)?;
+                }
+                OpCode::OP_INVOKE | OpCode::OP_INVOKE_LONG => {
+                    let method = self.read_string(long);
+                    let arg_count = self.read_byte() as usize;
+                    self.invoke(&method, arg_count)?;
+                }
+                OpCode::OP_SUPER_INVOKE | OpCode::OP_SUPER_INVOKE_LONG => {
+                    let method = self.read_string(long);
+                    let arg_count = self.read_byte() as usize;
+                    let superclass = match self.pop() {
+                        Value::Class(class) => class,
+                        value => panic!("Expected superclass, found {:?}", value),
+                    };
+                    self.invoke_from_class(&superclass, &method, arg_count)?;
+                }
+                OpCode::OP_CLOSURE | OpCode::OP_CLOSURE_LONG => {
+                    let function = match self.read_constant(long) {
+                        Value::Function(function) => function,
+                        value => panic!("Expected function constant, found {:?}", value),
+                    };
+                    let mut upvalues = Vec::with_capacity(function.upvalue_count);
+                    for _ in 0..function.upvalue_count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+                        if is_local {
+                            let slot = self.frame().slots + index;
+                            upvalues.push(self.capture_upvalue(slot));
+                        } else {
+                            upvalues.push(self.frame().closure.upvalues[index]);
+                        }
+                    }
+                    let closure = self.heap.alloc(ObjClosure::new(function, upvalues));
+                    self.push(Value::Closure(closure))?;
+                }
+                OpCode::OP_CLOSE_UPVALUE => {
+                    self.close_upvalues(self.stack_top - 1);
+                    self.pop();
                 }
                 OpCode::OP_RETURN => {
-                    println!("{:?}", self.pop());
-                    return InterpretResult::Ok;
+                    let result = self.pop();
+                    let frame = self.frames.pop().unwrap();
+                    self.close_upvalues(frame.slots);
+                    if self.frames.is_empty() {
+                        self.pop();
+                        return Ok(result);
+                    }
+
+                    self.stack_top = frame.slots;
+                    self.push(result)?;
+                }
+                OpCode::OP_CLASS | OpCode::OP_CLASS_LONG => {
+                    let name = self.read_string(long);
+                    let class = self.heap.alloc(ObjClass::new(name));
+                    self.push(Value::Class(class))?;
+                }
+                OpCode::OP_INHERIT => {
+                    let superclass = match self.peek(1) {
+                        Value::Class(class) => class,
+                        _ => return Err(self.runtime_error("Superclass must be a class.")),
+                    };
+                    if let Value::Class(subclass) = self.peek(0) {
+                        // copy-down inheritance, methods defined later override these
+                        let methods = superclass.methods.borrow().clone();
+                        let added = methods.len();
+                        subclass.methods.borrow_mut().extend(methods);
+                        self.heap.grow(subclass, added * METHOD_ENTRY_SIZE);
+                    }
+                    self.pop();
+                }
+                OpCode::OP_METHOD | OpCode::OP_METHOD_LONG => {
+                    let name = self.read_string(long);
+                    self.define_method(name);
                 }
             }
         }
     }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(source: &str) -> VM {
+        run_with(VM::new(), source)
+    }
+
+    fn run_with(mut vm: VM, source: &str) -> VM {
+        assert!(vm.interpret(source.to_string()).is_ok());
+        vm
+    }
+
+    fn global(vm: &mut VM, name: &str) -> Value {
+        let name = vm.heap.intern(name);
+        vm.globals.get(&name).copied().expect("global should be defined")
+    }
+
+    fn compile_errors(source: &str) -> Vec<String> {
+        let mut vm = VM::builder().stderr(std::io::sink()).build();
+        match vm.interpret(source.to_string()) {
+            Err(LoxError::Compile(diagnostics)) => diagnostics.into_iter().map(|d| d.message).collect(),
+            _ => panic!("expected compile errors"),
+        }
+    }
 
-    fn run(&mut self) -> InterpretResult {
-        loop {
-            let instruction: OpCode = self.read_byte().into();
+    fn stress_vm() -> VM {
+        let mut vm = VM::new();
+        vm.heap.stress = true;
+        vm
+    }
 
-            match instruction {
-                OpCode::OP_CONSTANT => {
-                    let constant = self.read_constant();
-                    self.push(constant);
-                }
-                OpCode::OP_ADD => binary_op!(self, +),
-                OpCode::OP_SUBTRACT => binary_op!(self, -),
-                OpCode::OP_MULTIPLY => binary_op!(self, *),
-                OpCode::OP_DIVIDE => binary_op!(self, /),
-                OpCode::OP_NEGATE => {
-                    let value = self.pop();
-                    self.push(-value);
+    #[test]
+    fn not_treats_only_nil_and_false_as_falsey() {
+        let mut vm = run("
+            var a = !nil;
+            var b = !false;
+            var c = !0;
+            var d = !\"\";
+            var e = !!true;
+        ");
+        assert_eq!(global(&mut vm, "a"), Value::Bool(true));
+        assert_eq!(global(&mut vm, "b"), Value::Bool(true));
+        assert_eq!(global(&mut vm, "c"), Value::Bool(false));
+        assert_eq!(global(&mut vm, "d"), Value::Bool(false));
+        assert_eq!(global(&mut vm, "e"), Value::Bool(true));
+    }
+
+    #[test]
+    fn equality_never_crosses_types_and_comparisons_desugar() {
+        let mut vm = run("
+            var nil_is_false = nil == false;
+            var number_is_string = 1 == \"1\";
+            var nil_is_nil = nil == nil;
+            var strings = \"a\" == \"a\";
+            var not_equal = 1 != 2;
+            var at_most = 1 <= 1;
+            var at_least = 2 >= 3;
+            var less = 1 < 2;
+        ");
+        assert_eq!(global(&mut vm, "nil_is_false"), Value::Bool(false));
+        assert_eq!(global(&mut vm, "number_is_string"), Value::Bool(false));
+        assert_eq!(global(&mut vm, "nil_is_nil"), Value::Bool(true));
+        assert_eq!(global(&mut vm, "strings"), Value::Bool(true));
+        assert_eq!(global(&mut vm, "not_equal"), Value::Bool(true));
+        assert_eq!(global(&mut vm, "at_most"), Value::Bool(true));
+        assert_eq!(global(&mut vm, "at_least"), Value::Bool(false));
+        assert_eq!(global(&mut vm, "less"), Value::Bool(true));
+    }
+
+    #[test]
+    fn arithmetic_on_non_numbers_is_a_runtime_error() {
+        let mut vm = VM::builder().stderr(std::io::sink()).build();
+        assert_eq!(runtime_error_message(&mut vm, "-\"a\";"), "Operand must be a number.");
+        for source in ["1 - nil;", "1 * true;", "\"a\" / 2;", "nil < 1;", "1 > \"x\";"] {
+            assert_eq!(runtime_error_message(&mut vm, source), "Operands must be numbers.", "{}", source);
+        }
+        assert_eq!(runtime_error_message(&mut vm, "1 + nil;"), "Operands must be two numbers or two strings.");
+    }
+
+    #[test]
+    fn local_scope_errors_and_shadowing() {
+        assert_eq!(
+            compile_errors("{ var a = 1; { var a = a; } }"),
+            vec!["Can't read local variable in its own initializer."]
+        );
+        let mut vm = VM::builder().stderr(std::io::sink()).build();
+        match vm.interpret("{ var a = 1; var a = 2; }".to_string()) {
+            Err(LoxError::Compile(diagnostics)) => {
+                assert_eq!(diagnostics.len(), 1);
+                assert_eq!(diagnostics[0].message, "Already a variable with this name in this scope.");
+                assert_eq!(diagnostics[0].notes, vec!["'a' was first declared at line 1:7."]);
+            }
+            _ => panic!("expected compile errors"),
+        }
+
+        let mut vm = run("
+            var outer;
+            var inner;
+            {
+                var a = 1;
+                {
+                    var a = 2;
+                    inner = a;
                 }
-                OpCode::OP_RETURN => {
-                    println!("{:?}", self.pop());
-                    return InterpretResult::Ok;
+                outer = a;
+            }
+        ");
+        assert_eq!(global(&mut vm, "outer"), Value::Number(1.0));
+        assert_eq!(global(&mut vm, "inner"), Value::Number(2.0));
+    }
+
+    #[test]
+    fn control_flow_picks_branches_and_short_circuits() {
+        let mut vm = run("
+            var calls = 0;
+            fun f() {
+                calls = calls + 1;
+                return true;
+            }
+            var and_result = false and f();
+            var or_result = true or f();
+            var evaluated = nil or f();
+
+            var branch;
+            if (1 > 2) branch = \"then\"; else branch = \"else\";
+            var taken;
+            if (2 > 1) taken = \"then\"; else taken = \"else\";
+
+            var count = 0;
+            while (count < 5) count = count + 1;
+        ");
+        assert_eq!(global(&mut vm, "and_result"), Value::Bool(false));
+        assert_eq!(global(&mut vm, "or_result"), Value::Bool(true));
+        assert_eq!(global(&mut vm, "evaluated"), Value::Bool(true));
+        // only the last operand had to call f
+        assert_eq!(global(&mut vm, "calls"), Value::Number(1.0));
+        let else_branch = vm.heap.intern("else");
+        assert_eq!(global(&mut vm, "branch"), Value::String(else_branch));
+        let then_branch = vm.heap.intern("then");
+        assert_eq!(global(&mut vm, "taken"), Value::String(then_branch));
+        assert_eq!(global(&mut vm, "count"), Value::Number(5.0));
+    }
+
+    #[test]
+    fn counter_factory_closures_keep_separate_state() {
+        let mut vm = run("
+            fun makeCounter() {
+                var count = 0;
+                fun increment() {
+                    count = count + 1;
+                    return count;
+                }
+                return increment;
+            }
+            var first = makeCounter();
+            var second = makeCounter();
+            first();
+            first();
+            var a = first();
+            var b = second();
+        ");
+        assert_eq!(global(&mut vm, "a"), Value::Number(3.0));
+        assert_eq!(global(&mut vm, "b"), Value::Number(1.0));
+    }
+
+    #[test]
+    fn closures_share_a_captured_variable() {
+        let mut vm = run("
+            var get;
+            var set;
+            fun makePair() {
+                var shared = 1;
+                fun getter() { return shared; }
+                fun setter(value) { shared = value; }
+                get = getter;
+                set = setter;
+            }
+            makePair();
+            set(42);
+            var result = get();
+        ");
+        assert_eq!(global(&mut vm, "result"), Value::Number(42.0));
+    }
+
+    #[test]
+    fn stress_collection_keeps_reachable_objects() {
+        let mut vm = run_with(stress_vm(), "
+            class Node {
+                init(value, next) {
+                    this.value = value;
+                    this.next = next;
                 }
             }
+            fun makeAdder(n) {
+                fun add(x) { return x + n; }
+                return add;
+            }
+            var list = nil;
+            for (var i = 0; i < 10; i = i + 1) {
+                list = Node(\"item\" + \"s\", list);
+            }
+            var add = makeAdder(2);
+            var result = add(40);
+            var name = list.next.value;
+        ");
+        assert_eq!(global(&mut vm, "result"), Value::Number(42.0));
+        let name = vm.heap.intern("items");
+        assert_eq!(global(&mut vm, "name"), Value::String(name));
+    }
+
+    #[test]
+    fn unreachable_cycles_are_freed() {
+        let mut vm = run("
+            class Node {}
+            for (var i = 0; i < 100; i = i + 1) {
+                var a = Node();
+                var b = Node();
+                a.other = b;
+                b.other = a;
+            }
+        ");
+        let before = vm.heap.bytes_allocated;
+        vm.collect_garbage();
+        assert!(vm.heap.bytes_allocated < before);
+    }
+
+    #[test]
+    fn natives_are_callable_from_lox() {
+        fn add(args: &[LoxValue]) -> Result<LoxValue, String> {
+            match (&args[0], &args[1]) {
+                (LoxValue::Number(a), LoxValue::Number(b)) => Ok(LoxValue::Number(a + b)),
+                _ => Err("Arguments must be numbers.".to_string()),
+            }
         }
+        let mut vm = VM::new();
+        vm.define_native("add", 2, add);
+        let mut vm = run_with(vm, "
+            var sum = add(1, 2);
+            var elapsed = clock() - clock();
+        ");
+        assert_eq!(global(&mut vm, "sum"), Value::Number(3.0));
+        assert!(matches!(global(&mut vm, "elapsed"), Value::Number(_)));
+        assert!(matches!(vm.interpret("add(1);".to_string()), Err(LoxError::Runtime(_))));
+        assert!(matches!(vm.interpret("add(1, nil);".to_string()), Err(LoxError::Runtime(_))));
+    }
+
+    #[test]
+    fn non_ascii_string_literals_keep_their_contents() {
+        let mut vm = run("var s = \"é\" + \"✓\";");
+        let expected = vm.heap.intern("é✓");
+        assert_eq!(global(&mut vm, "s"), Value::String(expected));
+    }
+
+    #[test]
+    fn literals_past_the_short_constant_limit_use_long_constants() {
+        let literals: Vec<String> = (0..1000).map(|n| n.to_string()).collect();
+        let source = format!("var sum = {};", literals.join(" + "));
+        let mut vm = run(&source);
+        assert_eq!(global(&mut vm, "sum"), Value::Number(499500.0));
+    }
+
+    #[test]
+    fn names_past_the_short_constant_limit_use_long_operands() {
+        let padding = (0..300).map(|n| n.to_string()).collect::<Vec<_>>().join(" + ");
+        let source = format!("
+            var sum = {padding};
+            class Base {{
+                get() {{ return this.value; }}
+            }}
+            class Point < Base {{
+                init(value) {{ this.value = value; }}
+                get() {{
+                    var pad = {padding};
+                    return super.get() + pad;
+                }}
+                bound() {{
+                    var pad = {padding};
+                    var method = super.get;
+                    return method();
+                }}
+            }}
+            fun make() {{ return Point(1); }}
+            var point = make();
+            point.value = 2;
+            var total = point.get();
+            var bound = point.bound();
+            sum = sum + point.value;
+        ");
+        let mut vm = run(&source);
+        assert_eq!(global(&mut vm, "total"), Value::Number(44852.0));
+        assert_eq!(global(&mut vm, "bound"), Value::Number(2.0));
+        assert_eq!(global(&mut vm, "sum"), Value::Number(44852.0));
+    }
+
+    #[test]
+    fn runtime_errors_carry_a_backtrace() {
+        let mut vm = VM::new();
+        let source = "fun inner() {\n  return nil + 1;\n}\nfun outer() {\n  inner();\n}\nouter();";
+        let error = match vm.interpret(source.to_string()) {
+            Err(LoxError::Runtime(error)) => error,
+            _ => panic!("expected a runtime error"),
+        };
+        assert_eq!(error.message, "Operands must be two numbers or two strings.");
+        assert_eq!(error.line, 2);
+        let trace: Vec<String> = error.backtrace.iter().map(|frame| frame.to_string()).collect();
+        assert_eq!(trace, vec![
+            "[line 2] in inner()",
+            "[line 5] in outer()",
+            "[line 7] in script",
+        ]);
+        // the column stays available to hosts through the span
+        let columns: Vec<usize> = error.backtrace.iter().map(|frame| frame.span.column).collect();
+        assert_eq!(columns, vec![14, 9, 7]);
+    }
+
+    #[test]
+    fn spans_point_into_the_script_each_function_came_from() {
+        let mut vm = run_with(VM::builder().stderr(std::io::sink()).build(), "fun f() {\n  return nil + 1;\n}");
+        let error = match vm.interpret("\n\nf();".to_string()) {
+            Err(LoxError::Runtime(error)) => error,
+            _ => panic!("expected a runtime error"),
+        };
+        let spans: Vec<Span> = error.backtrace.iter().map(|frame| frame.span).collect();
+        assert_eq!(spans, vec![Span::new(23, 24, 2, 14), Span::new(4, 5, 3, 3)]);
+    }
+
+    fn runtime_error_message(vm: &mut VM, source: &str) -> String {
+        match vm.interpret(source.to_string()) {
+            Err(LoxError::Runtime(error)) => error.message,
+            _ => panic!("expected a runtime error"),
+        }
+    }
+
+    #[test]
+    fn unbounded_recursion_overflows_the_call_stack() {
+        let mut vm = VM::new();
+        let message = runtime_error_message(&mut vm, "fun f(n) { return f(n + 1); } f(0);");
+        assert_eq!(message, "Stack overflow.");
+
+        // the vm is usable again after the error
+        let mut vm = run_with(vm, "var ok = true;");
+        assert_eq!(global(&mut vm, "ok"), Value::Bool(true));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn recursion_depth_follows_the_config() {
+        let source = "fun depth(n) { if (n == 0) return 0; return 1 + depth(n - 1); } var result = depth(500);";
+        let mut vm = VM::new();
+        assert_eq!(runtime_error_message(&mut vm, source), "Stack overflow.");
+
+        let config = VMConfig { frames_max: 1000, ..VMConfig::default() };
+        let mut vm = run_with(VM::builder().config(config).build(), source);
+        assert_eq!(global(&mut vm, "result"), Value::Number(500.0));
+    }
+
+    #[test]
+    fn an_unlimited_frame_count_builds() {
+        let config = VMConfig { frames_max: usize::MAX, ..VMConfig::default() };
+        let mut vm = run_with(VM::builder().config(config).build(), "fun f(n) { if (n > 0) f(n - 1); } f(100);");
+        // the value stack still bounds how deep calls can go
+        assert_eq!(runtime_error_message(&mut vm, "fun g() { g(); } g();"), "Stack overflow.");
+    }
+
+    #[test]
+    fn deep_expressions_overflow_a_small_value_stack() {
+        let nested = (0..40).fold("1".to_string(), |inner, n| format!("{} + ({})", n, inner));
+        let source = format!("var result = {};", nested);
+
+        let config = VMConfig { stack_max: 32, ..VMConfig::default() };
+        let mut vm = VM::builder().config(config).build();
+        assert_eq!(runtime_error_message(&mut vm, &source), "Stack overflow.");
+
+        let mut vm = run(&source);
+        assert_eq!(global(&mut vm, "result"), Value::Number(781.0));
+    }
+
+    #[test]
+    fn methods_bind_to_their_instance() {
+        let mut vm = run("
+            class Counter {
+                init(start) { this.count = start; }
+                next() {
+                    this.count = this.count + 1;
+                    return this.count;
+                }
+            }
+            var counter = Counter(10);
+            var next = counter.next;
+            next();
+            var result = next();
+            var field = counter.count;
+        ");
+        assert_eq!(global(&mut vm, "result"), Value::Number(12.0));
+        assert_eq!(global(&mut vm, "field"), Value::Number(12.0));
+    }
+
+    #[test]
+    fn initializers_check_arity_and_return_the_instance() {
+        let mut vm = run("
+            class Point {
+                init(x) {
+                    this.x = x;
+                    return;
+                }
+            }
+            var point = Point(1);
+            var again = point.init(2);
+            var x = point.x;
+        ");
+        assert_eq!(global(&mut vm, "again"), global(&mut vm, "point"));
+        assert_eq!(global(&mut vm, "x"), Value::Number(2.0));
+
+        assert_eq!(runtime_error_message(&mut vm, "Point();"), "Expected 1 arguments but got 0.");
+        assert_eq!(runtime_error_message(&mut vm, "class Empty {} Empty(1);"), "Expected 0 arguments but got 1.");
+        assert_eq!(
+            compile_errors("class Bad { init() { return 1; } }"),
+            vec!["Can't return a value from an initializer."]
+        );
+    }
+
+    #[test]
+    fn fields_shadow_methods_when_invoked() {
+        let mut vm = run("
+            class Box {
+                get() { return \"method\"; }
+            }
+            fun replacement() { return \"field\"; }
+            var box = Box();
+            var before = box.get();
+            box.get = replacement;
+            var after = box.get();
+        ");
+        let method = vm.heap.intern("method");
+        let field = vm.heap.intern("field");
+        assert_eq!(global(&mut vm, "before"), Value::String(method));
+        assert_eq!(global(&mut vm, "after"), Value::String(field));
+        assert_eq!(runtime_error_message(&mut vm, "box.missing();"), "Undefined property 'missing'.");
+    }
+
+    #[test]
+    fn super_calls_reach_the_superclass_method() {
+        let mut vm = run("
+            class A {
+                name() { return \"A\"; }
+            }
+            class B < A {
+                name() { return super.name() + \"B\"; }
+                bound() {
+                    var method = super.name;
+                    return method();
+                }
+            }
+            class C < B {}
+            var called = C().name();
+            var bound = C().bound();
+        ");
+        let called = vm.heap.intern("AB");
+        let bound = vm.heap.intern("A");
+        assert_eq!(global(&mut vm, "called"), Value::String(called));
+        assert_eq!(global(&mut vm, "bound"), Value::String(bound));
+    }
+
+    #[test]
+    fn misused_super_and_inheritance_are_compile_errors() {
+        assert_eq!(
+            compile_errors("class A { m() { return super.m(); } }"),
+            vec!["Can't use 'super' in a class with no superclass."]
+        );
+        assert_eq!(
+            compile_errors("fun f() { return super.m(); }"),
+            vec!["Can't use 'super' outside of a class."]
+        );
+        assert_eq!(compile_errors("class A < A {}"), vec!["A class can't inherit from itself."]);
+    }
+
+    #[test]
+    fn new_fields_and_methods_are_charged_to_the_heap() {
+        // a collection between the measurements would skew them
+        let mut vm = VM::new();
+        vm.heap.stress = false;
+        let mut vm = run_with(vm, "
+            class A {}
+            var a = A();
+            fun set(value) { a.x = value; }
+        ");
+        let charged = |vm: &mut VM, source: &str| {
+            let before = vm.heap.bytes_allocated;
+            vm.interpret(source.to_string()).unwrap();
+            vm.heap.bytes_allocated - before
+        };
+        // both scripts allocate the same function, only the first adds a field
+        let added = charged(&mut vm, "set(1);");
+        let replaced = charged(&mut vm, "set(2);");
+        assert_eq!(added - replaced, std::mem::size_of::<(Gc<ObjString>, Value)>());
+
+        charged(&mut vm, "class B { m() {} } class C { m() {} n() {} }");
+        // the subclasses differ only in how many methods are copied down
+        let one = charged(&mut vm, "class D < B {}");
+        let two = charged(&mut vm, "class E < C {}");
+        assert_eq!(two - one, METHOD_ENTRY_SIZE);
+    }
+}
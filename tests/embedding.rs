@@ -0,0 +1,213 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+
+use rlox::{LoxError, LoxValue, VMConfig, VM};
+
+// a writer the test keeps a handle to after giving it to the vm
+#[derive(Clone, Default)]
+struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl Capture {
+    fn contents(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn print_goes_to_the_configured_writer() {
+    let output = Capture::default();
+    let mut vm = VM::builder().stdout(output.clone()).build();
+
+    let result = vm.interpret("print 1 + 2; print \"lox\";".to_string());
+    assert_eq!(result, Ok(LoxValue::Nil));
+    assert_eq!(output.contents(), "3\nlox\n");
+}
+
+#[test]
+fn top_level_code_cannot_return_a_value() {
+    let mut vm = VM::builder().stderr(Capture::default()).build();
+
+    match vm.interpret("return 1;".to_string()) {
+        Err(LoxError::Compile(diagnostics)) => assert_eq!(diagnostics[0].message, "Can't return from top-level code."),
+        other => panic!("expected a compile error, got {:?}", other),
+    }
+}
+
+// a writer whose reader has gone away
+struct Closed;
+
+impl Write for Closed {
+    fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+        Err(std::io::Error::new(std::io::ErrorKind::BrokenPipe, "closed"))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn failed_prints_are_runtime_errors() {
+    let mut vm = VM::builder().stdout(Closed).stderr(Capture::default()).build();
+
+    match vm.interpret("print 1;".to_string()) {
+        Err(LoxError::Runtime(error)) => assert_eq!(error.message, "Error writing output: closed."),
+        other => panic!("expected a runtime error, got {:?}", other),
+    }
+}
+
+#[test]
+fn failing_diagnostic_writers_are_ignored() {
+    let mut vm = VM::builder().stdout(Capture::default()).stderr(Closed).trace(Closed).build();
+
+    assert!(matches!(vm.interpret("print ;".to_string()), Err(LoxError::Compile(_))));
+    assert!(matches!(vm.interpret("print -nil;".to_string()), Err(LoxError::Runtime(_))));
+    vm.collect_garbage();
+    assert_eq!(vm.interpret("print 1;".to_string()), Ok(LoxValue::Nil));
+}
+
+#[test]
+fn globals_persist_between_interpret_calls() {
+    let output = Capture::default();
+    let mut vm = VM::builder().stdout(output.clone()).build();
+
+    vm.interpret("var greeting = \"hello\";".to_string()).unwrap();
+    vm.interpret("print greeting + \" world\";".to_string()).unwrap();
+    assert_eq!(output.contents(), "hello world\n");
+}
+
+#[test]
+fn hosts_can_register_natives() {
+    fn square(args: &[LoxValue]) -> Result<LoxValue, String> {
+        match args[0] {
+            LoxValue::Number(n) => Ok(LoxValue::Number(n * n)),
+            _ => Err("Argument must be a number.".to_string()),
+        }
+    }
+
+    let output = Capture::default();
+    let mut vm = VM::builder().stdout(output.clone()).build();
+    vm.define_native("square", 1, square);
+
+    vm.interpret("print square(12);".to_string()).unwrap();
+    assert_eq!(output.contents(), "144\n");
+}
+
+#[test]
+fn natives_see_strings_by_value_and_objects_by_description() {
+    fn describe(args: &[LoxValue]) -> Result<LoxValue, String> {
+        match &args[0] {
+            LoxValue::String(s) => Ok(LoxValue::String(format!("string {}", s).into())),
+            LoxValue::Object(description) => Ok(LoxValue::String(format!("object {}", description).into())),
+            other => Ok(LoxValue::String(format!("value {}", other).into())),
+        }
+    }
+    fn echo(args: &[LoxValue]) -> Result<LoxValue, String> {
+        Ok(args[0].clone())
+    }
+
+    let output = Capture::default();
+    let mut vm = VM::builder().stdout(output.clone()).stderr(Capture::default()).build();
+    vm.define_native("describe", 1, describe);
+    vm.define_native("echo", 1, echo);
+
+    vm.interpret("class Point {} print describe(\"lox\"); print describe(Point()); print describe(2);".to_string())
+        .unwrap();
+    assert_eq!(output.contents(), "string lox\nobject Point instance\nvalue 2\n");
+
+    // a returned string is interned again, so it compares equal in lox
+    vm.interpret("print echo(\"lox\") == \"lox\";".to_string()).unwrap();
+    assert_eq!(output.contents(), "string lox\nobject Point instance\nvalue 2\ntrue\n");
+
+    match vm.interpret("echo(Point);".to_string()) {
+        Err(LoxError::Runtime(error)) => assert_eq!(error.message, "Native functions can't return objects."),
+        other => panic!("expected a runtime error, got {:?}", other),
+    }
+}
+
+#[test]
+fn errors_are_reported_through_the_result() {
+    let config = VMConfig { frames_max: 8, ..VMConfig::default() };
+    let mut vm = VM::builder().config(config).stdout(Capture::default()).stderr(Capture::default()).build();
+
+    match vm.interpret("print ;\nvar = 1;".to_string()) {
+        Err(LoxError::Compile(diagnostics)) => assert_eq!(diagnostics.len(), 2),
+        _ => panic!("expected compile errors"),
+    }
+    let error = vm.interpret("fun f() { f(); } f();".to_string()).unwrap_err();
+    assert_eq!(error.exit_code(), 70);
+    match error {
+        LoxError::Runtime(error) => {
+            assert_eq!(error.message, "Stack overflow.");
+            assert_eq!(error.line, 1);
+            assert_eq!(error.backtrace.len(), 8);
+        }
+        _ => panic!("expected a runtime error"),
+    }
+}
+
+#[test]
+fn diagnostics_go_to_the_configured_writer() {
+    let output = Capture::default();
+    let errors = Capture::default();
+    let mut vm = VM::builder()
+        .stdout(output.clone())
+        .stderr(errors.clone())
+        .diagnostic_style(rlox::DiagnosticStyle::Plain)
+        .build();
+
+    vm.interpret("print 1 +;".to_string()).unwrap_err();
+    assert_eq!(errors.contents(), "[line 1] Error at ';': Expect expression.\n");
+
+    vm.interpret("print \"before\";\nprint -nil;".to_string()).unwrap_err();
+    assert_eq!(output.contents(), "before\n");
+    assert!(errors.contents().ends_with("Operand must be a number.\n[line 2] in script\n"));
+}
+
+#[cfg(feature = "debug_print_code")]
+#[test]
+fn disassembly_goes_to_the_trace_writer() {
+    let trace = Capture::default();
+    let mut vm = VM::builder().stdout(Capture::default()).trace(trace.clone()).build();
+
+    vm.interpret("print 1;".to_string()).unwrap();
+    assert!(trace.contents().contains("OP_PRINT"));
+}
+
+#[cfg(feature = "debug_log_gc")]
+#[test]
+fn gc_log_goes_to_the_trace_writer() {
+    let trace = Capture::default();
+    let errors = Capture::default();
+    let mut vm = VM::builder().stderr(errors.clone()).trace(trace.clone()).build();
+
+    vm.collect_garbage();
+    assert!(trace.contents().contains("-- gc collected"));
+    assert_eq!(errors.contents(), "");
+}
+
+#[test]
+fn lox_errors_work_as_std_errors() {
+    let mut vm = VM::builder().stderr(Capture::default()).build();
+
+    let error: Box<dyn std::error::Error> = Box::new(vm.interpret("var 1;".to_string()).unwrap_err());
+    assert_eq!(error.to_string(), "[line 1] Error at '1': Expect variable name.\n");
+    assert!(error.source().is_none());
+
+    let error = vm.interpret("nil();".to_string()).unwrap_err();
+    assert_eq!(error.exit_code(), 70);
+    let error: &dyn std::error::Error = &error;
+    assert_eq!(error.source().unwrap().to_string(), "Can only call functions and classes.\n[line 1] in script\n");
+}